@@ -15,7 +15,63 @@ macro_rules! derive_error {
     };
 }
 
-#[proc_macro_derive(GetParams)]
+/// Returns the type as a string without whitespace, e.g. `Option<Effect>`.
+fn type_string(ty: &Type) -> String {
+    ty.to_token_stream().to_string().replace(' ', "")
+}
+
+/// Returns whether the field is marked with `#[flatten_params]`, expanding it into multiple params.
+fn is_flattened(field: &syn::Field) -> bool {
+    field
+        .attrs
+        .iter()
+        .any(|attr| attr.path().is_ident("flatten_params"))
+}
+
+/// Returns whether the field is marked with `#[transition]`, making it the effect of a transition whose duration is the next field.
+fn is_transition(field: &syn::Field) -> bool {
+    field
+        .attrs
+        .iter()
+        .any(|attr| attr.path().is_ident("transition"))
+}
+
+/// Returns the name the variant is sent with, honoring `#[serde(rename = "...")]`.
+fn wire_name(variant: &syn::Variant) -> syn::Result<String> {
+    let mut name = variant.ident.to_string().to_case(Case::Snake);
+    for attr in variant
+        .attrs
+        .iter()
+        .filter(|attr| attr.path().is_ident("serde"))
+    {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("rename") && meta.input.peek(syn::Token![=]) {
+                name = meta.value()?.parse::<syn::LitStr>()?.value();
+            } else if meta.input.peek(syn::Token![=]) {
+                meta.value()?.parse::<syn::Expr>()?;
+            } else if meta.input.peek(syn::token::Paren) {
+                let _content;
+                syn::parenthesized!(_content in meta.input);
+            }
+            Ok(())
+        })?;
+    }
+
+    Ok(name)
+}
+
+/// Returns the guard of a variant marked with `#[from_raw_guard(...)]`,
+/// which is only constructed from a raw command if the guard holds.
+fn from_raw_guard(variant: &syn::Variant) -> syn::Result<Option<syn::Expr>> {
+    variant
+        .attrs
+        .iter()
+        .find(|attr| attr.path().is_ident("from_raw_guard"))
+        .map(|attr| attr.parse_args())
+        .transpose()
+}
+
+#[proc_macro_derive(GetParams, attributes(flatten_params, transition))]
 pub fn get_params_derive(input: TokenStream) -> TokenStream {
     let ast = syn::parse(input).unwrap();
     impl_get_params(&ast)
@@ -42,7 +98,6 @@ fn impl_get_params(ast: &syn::DeriveInput) -> TokenStream {
                         let mut field_names = TokenStream2::new();
                         let mut vec_extends = TokenStream2::new();
                         let num_fields = fields.unnamed.len();
-                        let mut field_num: usize = 0;
 
                         let vec_name = format!(
                             "__{}_json_values_vec",
@@ -50,7 +105,9 @@ fn impl_get_params(ast: &syn::DeriveInput) -> TokenStream {
                         );
                         let vec_identifier = format_ident!("{}", vec_name);
 
-                        for field in fields.unnamed.iter() {
+                        let mut duration_pushed = false;
+
+                        for (field_num, field) in fields.unnamed.iter().enumerate() {
                             let field_name = format_ident!(
                                 "{}",
                                 format!(
@@ -59,7 +116,6 @@ fn impl_get_params(ast: &syn::DeriveInput) -> TokenStream {
                                     field_num
                                 )
                             );
-                            field_num += 1;
                             field_names.extend(quote_spanned! { field.span() =>
                                 #field_name,
                             });
@@ -68,6 +124,63 @@ fn impl_get_params(ast: &syn::DeriveInput) -> TokenStream {
                             //     println!("{}", type_path.into_token_stream());
                             // }
 
+                            // the duration was already pushed together with its effect
+                            if duration_pushed {
+                                duration_pushed = false;
+                                continue;
+                            }
+
+                            // a flattened field expands into multiple params through its own get_params
+                            if is_flattened(field) {
+                                vec_extends.extend(quote_spanned! {variant.span()=>
+                                #vec_identifier.extend(#field_name.get_params());});
+                                continue;
+                            }
+
+                            // an effect followed by a duration is pushed as a transition
+                            if is_transition(field) {
+                                if field_num + 1 >= num_fields {
+                                    return Error::new(
+                                        field.span(),
+                                        "a #[transition] field has to be followed by its duration",
+                                    )
+                                    .to_compile_error()
+                                    .into();
+                                }
+
+                                let duration_name = format_ident!(
+                                    "{}",
+                                    format!(
+                                        "__{}_{}",
+                                        variant_name.to_string().to_case(Case::Snake),
+                                        field_num + 1
+                                    )
+                                );
+                                // the transition has to be sent in full if any later param is sent after it
+                                let mut followed = quote! { false };
+                                for (later_num, later) in
+                                    fields.unnamed.iter().enumerate().skip(field_num + 2)
+                                {
+                                    let later_name = format_ident!(
+                                        "{}",
+                                        format!(
+                                            "__{}_{}",
+                                            variant_name.to_string().to_case(Case::Snake),
+                                            later_num
+                                        )
+                                    );
+                                    if type_string(&later.ty).starts_with("Option<") {
+                                        followed.extend(quote! { || #later_name.is_some() });
+                                    } else {
+                                        followed = quote! { true };
+                                    }
+                                }
+                                vec_extends.extend(quote_spanned! {variant.span()=>
+                                crate::method::push_transition(&mut #vec_identifier, #field_name.clone(), *#duration_name, #followed);});
+                                duration_pushed = true;
+                                continue;
+                            }
+
                             match &field.ty {
                                 Type::Path(type_path)
                                     if type_path.clone().into_token_stream().to_string()
@@ -229,7 +342,7 @@ fn impl_into_json_value_derive(ast: &syn::DeriveInput) -> TokenStream {
     TokenStream::from(expanded)
 }
 
-#[proc_macro_derive(FromRawCommand)]
+#[proc_macro_derive(FromRawCommand, attributes(flatten_params, from_raw_guard))]
 pub fn from_raw_command_derive(input: TokenStream) -> TokenStream {
     let ast = syn::parse(input).unwrap();
     impl_from_raw_command_derive(&ast)
@@ -240,6 +353,8 @@ fn impl_from_raw_command_derive(ast: &syn::DeriveInput) -> TokenStream {
     let data = &ast.data;
 
     let mut method_construction;
+    // guarded arms share their name with another variant, so they have to come first
+    let mut guarded_construction = TokenStream2::new();
 
     match data {
         Data::Enum(data_enum) => {
@@ -247,38 +362,52 @@ fn impl_from_raw_command_derive(ast: &syn::DeriveInput) -> TokenStream {
 
             for variant in &data_enum.variants {
                 let variant_name = &variant.ident;
-                let variant_snake_case = variant_name.to_string().to_case(Case::Snake).to_string();
+                let variant_snake_case = match wire_name(variant) {
+                    Ok(name) => name,
+                    Err(e) => return e.to_compile_error().into(),
+                };
+                let guard = match from_raw_guard(variant) {
+                    Ok(Some(guard)) => quote! { if #guard },
+                    Ok(None) => TokenStream2::new(),
+                    Err(e) => return e.to_compile_error().into(),
+                };
+                let construction = if guard.is_empty() {
+                    &mut method_construction
+                } else {
+                    &mut guarded_construction
+                };
 
                 let mut param_construction = TokenStream2::new();
 
                 match &variant.fields {
                     Fields::Unnamed(fields) => {
                         for (i, field) in fields.unnamed.iter().enumerate() {
-                            match &field.ty {
-                                Type::Path(type_path)
-                                    if type_path.clone().into_token_stream().to_string()
-                                        == "bool" =>
-                                {
-                                    param_construction.extend(quote_spanned! {variant.span()=>
-                                        if raw.params.len() > #i { match raw.params[#i].as_str().unwrap() { "on" => true, "off" => false, _ => false } } else { panic!("Value for non optional field '{} - {}' in '{}' is missing", #i+1, stringify!(#field), stringify!(#variant_name)) },
-                                    });
-                                }
-                                Type::Path(type_path)
-                                    if type_path
-                                        .clone()
-                                        .into_token_stream()
-                                        .to_string()
-                                        .starts_with("Option <") =>
-                                {
-                                    param_construction.extend(quote_spanned! {variant.span()=>
-                                                if raw.params.len() > #i { Some(serde_json::from_value(raw.params[#i].to_owned()).unwrap()) } else { None },
-                                            });
-                                }
-                                _ => {
-                                    param_construction.extend(quote_spanned! {variant.span()=>
-                                        if raw.params.len() > #i { serde_json::from_value(raw.params[#i].to_owned()).unwrap() } else { panic!("Value for non optional field '{} - {}' in '{}' is missing", #i+1, stringify!(#field), stringify!(#variant_name)) },
-                                    });
-                                }
+                            if is_flattened(field) {
+                                let ty = &field.ty;
+                                param_construction.extend(quote_spanned! {variant.span()=>
+                                    <#ty>::from_params(&raw.params[#i.min(raw.params.len())..])?,
+                                });
+                                continue;
+                            }
+
+                            let ty = type_string(&field.ty);
+                            if ty.starts_with("Vec<") {
+                                // a vec takes all remaining params
+                                param_construction.extend(quote_spanned! {variant.span()=>
+                                    raw.remaining_params(#i)?,
+                                });
+                            } else if ty == "bool" {
+                                param_construction.extend(quote_spanned! {variant.span()=>
+                                    raw.power_param(#i)?,
+                                });
+                            } else if ty.starts_with("Option<") {
+                                param_construction.extend(quote_spanned! {variant.span()=>
+                                    raw.optional_param(#i)?,
+                                });
+                            } else {
+                                param_construction.extend(quote_spanned! {variant.span()=>
+                                    raw.param(#i)?,
+                                });
                             }
                         }
                     }
@@ -287,12 +416,12 @@ fn impl_from_raw_command_derive(ast: &syn::DeriveInput) -> TokenStream {
                 };
 
                 if param_construction.is_empty() {
-                    method_construction.extend(quote_spanned! {variant.span()=>
-                        #variant_snake_case => #name::#variant_name,
+                    construction.extend(quote_spanned! {variant.span()=>
+                        #variant_snake_case #guard => #name::#variant_name,
                     });
                 } else {
-                    method_construction.extend(quote_spanned! {variant.span()=>
-                        #variant_snake_case => #name::#variant_name(#param_construction),
+                    construction.extend(quote_spanned! {variant.span()=>
+                        #variant_snake_case #guard => #name::#variant_name(#param_construction),
                     });
                 }
             }
@@ -302,21 +431,29 @@ fn impl_from_raw_command_derive(ast: &syn::DeriveInput) -> TokenStream {
 
     let (impl_generics, ty_generics, where_clause) = ast.generics.split_for_impl();
 
+    // missing or ill-typed params and unknown methods are returned as errors, so logged commands can be parsed safely
     let expanded = quote! {
-        impl From<RawCommand> for #impl_generics #name #ty_generics #where_clause {
-            fn from(raw: RawCommand) -> Self {
-                match raw.method.as_str() {
-                    #method_construction
-                    _ => panic!("Unknown method"),
-                }
+        impl TryFrom<RawCommand> for #impl_generics #name #ty_generics #where_clause {
+            type Error = serde_json::Error;
+
+            fn try_from(raw: RawCommand) -> Result<Self, Self::Error> {
+                Self::try_from(&raw)
             }
         }
-        impl From<&RawCommand> for #impl_generics #name #ty_generics #where_clause {
-            fn from(raw: &RawCommand) -> Self {
-                match raw.method.as_str() {
+        impl TryFrom<&RawCommand> for #impl_generics #name #ty_generics #where_clause {
+            type Error = serde_json::Error;
+
+            fn try_from(raw: &RawCommand) -> Result<Self, Self::Error> {
+                Ok(match raw.method.as_str() {
+                    #guarded_construction
                     #method_construction
-                    _ => panic!("Unknown method"),
-                }
+                    method => {
+                        return Err(<serde_json::Error as serde::de::Error>::custom(format!(
+                            "unknown method '{}'",
+                            method
+                        )))
+                    }
+                })
             }
         }
     };
@@ -1,5 +1,8 @@
-use crate::{method::Method, property::Property};
-use serde::{Deserialize, Serialize, Serializer};
+use crate::{
+    method::{Method, Transition},
+    property::Property,
+};
+use serde::{de, de::DeserializeOwned, Deserialize, Serialize, Serializer};
 use std::collections::HashMap;
 
 #[derive(Serialize, Deserialize, PartialEq, Eq, Debug)]
@@ -10,6 +13,73 @@ pub(crate) struct RawCommand {
     pub params: Vec<serde_json::Value>,
 }
 
+impl RawCommand {
+    /// Parses the param at `index`, failing if it is missing.
+    pub(crate) fn param<T: DeserializeOwned>(&self, index: usize) -> Result<T, serde_json::Error> {
+        match self.params.get(index) {
+            Some(param) => self.parse(index, param),
+            None => Err(de::Error::custom(format!(
+                "param {} of '{}' is missing",
+                index + 1,
+                self.method
+            ))),
+        }
+    }
+
+    /// Parses the param at `index`, if it is given.
+    pub(crate) fn optional_param<T: DeserializeOwned>(
+        &self,
+        index: usize,
+    ) -> Result<Option<T>, serde_json::Error> {
+        self.params
+            .get(index)
+            .map(|param| self.parse(index, param))
+            .transpose()
+    }
+
+    /// Parses all params starting at `index`.
+    pub(crate) fn remaining_params<T: DeserializeOwned>(
+        &self,
+        index: usize,
+    ) -> Result<Vec<T>, serde_json::Error> {
+        self.params
+            .iter()
+            .enumerate()
+            .skip(index)
+            .map(|(index, param)| self.parse(index, param))
+            .collect()
+    }
+
+    /// Parses the power state at `index`, sent as `"on"` or `"off"`.
+    pub(crate) fn power_param(&self, index: usize) -> Result<bool, serde_json::Error> {
+        match self.param::<String>(index)?.as_str() {
+            "on" => Ok(true),
+            "off" => Ok(false),
+            power => Err(de::Error::custom(format!(
+                "param {} of '{}' has to be \"on\" or \"off\", got \"{}\"",
+                index + 1,
+                self.method,
+                power
+            ))),
+        }
+    }
+
+    fn parse<T: DeserializeOwned>(
+        &self,
+        index: usize,
+        param: &serde_json::Value,
+    ) -> Result<T, serde_json::Error> {
+        T::deserialize(param).map_err(|e| {
+            de::Error::custom(format!(
+                "param {} of '{}' is invalid: {}",
+                index + 1,
+                self.method,
+                e
+            ))
+        })
+    }
+}
+
 fn variant_name_only<S>(method: &Method, serializer: S) -> Result<S::Ok, S::Error>
 where
     S: Serializer,
@@ -23,9 +93,9 @@ where
 /// The command is serialized to JSON and sent to the device.
 ///
 /// [`Command`]s are created using the [`Command::new`] function.
-#[derive(Serialize, Deserialize, PartialEq, Eq, Debug)]
-#[serde(rename_all = "snake_case", from = "RawCommand")]
-// TODO: implement custom deserializer to get the method enum values from the params
+/// Deserializing a command fails if its method is unknown or its params don't match the method.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case", try_from = "RawCommand")]
 pub struct Command {
     /// The unique ID of the command.
     pub id: i32,
@@ -36,13 +106,15 @@ pub struct Command {
     pub params: Vec<serde_json::Value>,
 }
 
-impl From<RawCommand> for Command {
-    fn from(raw: RawCommand) -> Self {
-        Command {
+impl TryFrom<RawCommand> for Command {
+    type Error = serde_json::Error;
+
+    fn try_from(raw: RawCommand) -> Result<Self, Self::Error> {
+        Ok(Command {
             id: raw.id,
-            method: Method::from(&raw),
+            method: Method::try_from(&raw)?,
             params: raw.params,
-        }
+        })
     }
 }
 
@@ -55,6 +127,11 @@ impl Command {
             method,
         }
     }
+
+    /// Returns the [`Transition`] of the command's method, if it has one.
+    pub fn transition(&self) -> Option<Transition> {
+        self.method.transition()
+    }
 }
 
 /// A response from a device, containing the echoed ID of the Command, a result and optional Error.
@@ -62,15 +139,16 @@ impl Command {
 pub struct CommandResponse {
     /// The unique, echoed ID of the command.
     pub id: i32,
-    /// The result of the command.
+    /// The result of the command, empty if the command failed.
+    #[serde(default)]
     pub result: Vec<CommandResult>,
     /// The error of the command, if any.
     pub error: Option<CommandResponseError>,
 }
 
 /// The result of a [`Command`].
-#[derive(Serialize, Deserialize, PartialEq, Eq, Debug)]
-#[serde(rename_all = "snake_case")]
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
+#[serde(from = "serde_json::Value", into = "serde_json::Value")]
 pub enum CommandResult {
     /// The command was successful ("ok").
     Ok,
@@ -78,6 +156,79 @@ pub enum CommandResult {
     Off,
     /// State is on
     On,
+    /// Any other value, e.g. a property value returned by `get_prop` like "50" for the brightness
+    Value(String),
+    /// A structured value, e.g. a timer object returned by `cron_get`
+    Json(serde_json::Value),
+}
+
+impl CommandResult {
+    /// Returns the result as the string sent by the device, or `None` for a structured [`CommandResult::Json`] result.
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            CommandResult::Ok => Some("ok"),
+            CommandResult::Off => Some("off"),
+            CommandResult::On => Some("on"),
+            CommandResult::Value(value) => Some(value),
+            CommandResult::Json(_) => None,
+        }
+    }
+}
+
+impl From<serde_json::Value> for CommandResult {
+    fn from(value: serde_json::Value) -> Self {
+        match value {
+            serde_json::Value::String(value) => match value.as_str() {
+                "ok" => CommandResult::Ok,
+                "off" => CommandResult::Off,
+                "on" => CommandResult::On,
+                _ => CommandResult::Value(value),
+            },
+            value @ (serde_json::Value::Object(_) | serde_json::Value::Array(_)) => {
+                CommandResult::Json(value)
+            }
+            // some firmware sends numbers instead of strings
+            value => CommandResult::Value(value.to_string()),
+        }
+    }
+}
+
+impl From<CommandResult> for serde_json::Value {
+    fn from(result: CommandResult) -> Self {
+        match result {
+            CommandResult::Ok => "ok".into(),
+            CommandResult::Off => "off".into(),
+            CommandResult::On => "on".into(),
+            CommandResult::Value(value) => value.into(),
+            CommandResult::Json(value) => value,
+        }
+    }
+}
+
+/// A timer of a device, as returned by [`Method::CronGet`].
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
+pub struct CronEntry {
+    /// The type of the timer, 0 for power off.
+    #[serde(rename = "type")]
+    pub type_: i32,
+    /// The remaining delay of the timer in minutes.
+    pub delay: i32,
+    /// Unused by the device, always 0.
+    pub mix: i32,
+}
+
+impl CronEntry {
+    /// Parses the timers of a [`Method::CronGet`] response.
+    ///
+    /// # Errors
+    /// * `serde_json::Error` - If a result isn't a timer object.
+    pub fn from_response(response: &CommandResponse) -> Result<Vec<CronEntry>, serde_json::Error> {
+        response
+            .result
+            .iter()
+            .map(|result| serde_json::from_value(result.clone().into()))
+            .collect()
+    }
 }
 
 /// The error of a [`Command`], containing a error code and a description.
@@ -90,10 +241,70 @@ pub struct CommandResponseError {
 }
 
 /// Notification from a device, containing a method and changed properties.
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
 pub struct NotificationResult {
     /// The method of the notification.
-    pub method: String,
-    /// The properties of the notification.
-    pub params: HashMap<Property, serde_json::Value>,
+    pub method: NotificationMethod,
+    /// The raw params of the notification, keyed by name.
+    /// Keys which aren't a known [`Property`], e.g. `main_power`, are kept as well.
+    pub params: HashMap<String, serde_json::Value>,
+}
+
+impl NotificationResult {
+    /// Returns the changed [`Property`]s with their raw values, skipping params which aren't a known [`Property`].
+    /// Only [`NotificationMethod::Props`] notifications report property changes, other methods return none.
+    pub fn property_changes(&self) -> Vec<PropertyChange> {
+        if self.method != NotificationMethod::Props {
+            return Vec::new();
+        }
+
+        self.params
+            .iter()
+            .filter_map(|(key, value)| {
+                let property = serde_json::from_value(key.as_str().into()).ok()?;
+                let value = match value {
+                    serde_json::Value::String(value) => value.clone(),
+                    value => value.to_string(),
+                };
+                Some(PropertyChange { property, value })
+            })
+            .collect()
+    }
+}
+
+/// The method of a [`NotificationResult`].
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
+#[serde(from = "String", into = "String")]
+pub enum NotificationMethod {
+    /// Properties of the device changed ("props").
+    Props,
+    /// Any other method, e.g. one introduced by newer firmware.
+    Other(String),
+}
+
+impl From<String> for NotificationMethod {
+    fn from(method: String) -> Self {
+        match method.as_str() {
+            "props" => NotificationMethod::Props,
+            _ => NotificationMethod::Other(method),
+        }
+    }
+}
+
+impl From<NotificationMethod> for String {
+    fn from(method: NotificationMethod) -> Self {
+        match method {
+            NotificationMethod::Props => "props".to_string(),
+            NotificationMethod::Other(method) => method,
+        }
+    }
+}
+
+/// A single property change reported by a device notification.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct PropertyChange {
+    /// The property which changed.
+    pub property: Property,
+    /// The new raw value of the property.
+    pub value: String,
 }
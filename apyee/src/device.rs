@@ -1,19 +1,36 @@
 use crate::{
-    command::{Command, CommandResponse, NotificationResult},
-    method::Method,
+    command::{
+        Command, CommandResponse, CommandResponseError, CommandResult, CronEntry,
+        NotificationResult, PropertyChange, RawCommand,
+    },
+    flow::{FlowAction, FlowExpression},
+    method::{AdjustAction, AdjustProp, Effect, Macro, Method, PowerMode, PowerOnMode, Scene},
+    property::Property,
 };
-use rand::Rng;
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet, VecDeque},
     net::SocketAddr,
-    sync::{atomic::AtomicI32, Arc},
+    ops::{Range, RangeInclusive},
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, AtomicI32, Ordering},
+        Arc,
+    },
+    time::Duration,
 };
 use thiserror::Error;
-use tokio::io;
 use tokio::{
-    io::AsyncWriteExt,
-    net::TcpStream,
-    sync::{Mutex, Notify},
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{
+        tcp::{OwnedReadHalf, OwnedWriteHalf},
+        TcpListener, TcpStream,
+    },
+    sync::{broadcast, futures::Notified, Mutex, Notify},
+    task::JoinHandle,
+    time::Instant,
 };
 
 /// Default Port of Yeelight Bulbs
@@ -34,6 +51,348 @@ pub enum DeviceError {
     #[error(transparent)]
     /// Error when a response contains invalid utf8
     Utf8(#[from] std::str::Utf8Error),
+    /// Error when an argument or configuration value is invalid
+    #[error("invalid argument: {0}")]
+    InvalidArgument(String),
+    /// Error when a response doesn't contain the expected result
+    #[error("unexpected response: {0:?}")]
+    UnexpectedResponse(CommandResponse),
+    /// Error when the connection to the Yeelight Bulb was closed and isn't reconnected automatically
+    #[error("connection closed")]
+    ConnectionClosed,
+    /// Error reported by the Yeelight Bulb in response to a command
+    #[error("device error {code}: {message}")]
+    Device {
+        /// The error code sent by the device.
+        code: i32,
+        /// The error description sent by the device.
+        message: String,
+    },
+}
+
+#[derive(Deserialize)]
+struct JsonRequest {
+    method: String,
+    #[serde(default)]
+    params: Vec<serde_json::Value>,
+}
+
+fn json_error(error: &dyn std::error::Error) -> serde_json::Value {
+    serde_json::json!({ "error": { "message": error.to_string() } })
+}
+
+/// Configuration of a [`Device`], used with [`Device::new_with_config`].
+/// It can be (de)serialized, e.g. to load it from a config file. Missing fields take their default value
+/// and durations are given in milliseconds.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
+#[serde(default)]
+pub struct DeviceConfig {
+    /// The range the randomized id of the first command is picked from.
+    /// Assigning disjoint ranges to devices in the same process helps to correlate their logs.
+    pub id_range: Range<i32>,
+    /// The id of the first command, e.g. persisted from [`Device::current_id`] before a restart.
+    /// Takes precedence over [`DeviceConfig::id_range`].
+    pub start_id: Option<i32>,
+    /// The number of most recently sent commands kept for [`Device::recent_commands`].
+    pub history_size: usize,
+    /// When set, commands are serialized and recorded in the history, but not sent to the device.
+    /// Every command is answered with a synthetic `ok` response.
+    /// [`Device::new_with_config`] doesn't connect to the device then, so command sequences can be validated offline.
+    pub dry_run: bool,
+    /// The maximum length in bytes of a serialized command.
+    /// Longer commands are rejected instead of risking to overflow the device's input buffer and wedge the connection.
+    pub max_command_len: usize,
+    /// When set, [`Device::set_rgb`], [`Device::set_bg_rgb`] and [`Device::set_bright`] skip sending
+    /// if the same value was successfully set within this duration and no other command changing the state was sent since,
+    /// reads like [`Device::get_property`] don't count.
+    /// Saves quota on idempotent re-sets, e.g. in polling loops.
+    #[serde(with = "option_duration_millis")]
+    pub dedup_ttl: Option<Duration>,
+    /// Whether the device supports smooth transitions. Some cheap devices ignore them,
+    /// so when unset, [`Effect::Smooth`] transitions are sent as [`Effect::Sudden`] instead.
+    pub supports_smooth: bool,
+    /// Whether the device accepts the mode parameter of `set_power`, which e.g. ceiling lights do.
+    /// When unset, [`Device::power_on_with_mode`] turns the device on without switching the mode.
+    pub supports_power_mode: bool,
+    /// When set, a closed connection is reconnected before the next command is sent.
+    /// Otherwise commands fail with `DeviceError::ConnectionClosed`.
+    pub auto_reconnect: bool,
+    /// The number of connection attempts when reconnecting.
+    pub reconnect_attempts: u32,
+    /// The delay before the second reconnection attempt, doubled after every further failed attempt.
+    #[serde(with = "duration_millis")]
+    pub reconnect_backoff: Duration,
+    /// The maximum time to wait for the response to a command.
+    #[serde(with = "duration_millis")]
+    pub timeout: Duration,
+    /// What happens to responses whose id doesn't match a command waiting for its response.
+    /// Not (de)serialized, since a callback can't be; it is [`OrphanPolicy::Cache`] when deserialized.
+    #[serde(skip)]
+    pub orphan_responses: OrphanPolicy,
+}
+
+/// What happens to a response whose id doesn't match a command waiting for its response,
+/// e.g. a late response to a timed out command or a response to another client connected to the same device.
+#[derive(Clone, Default)]
+pub enum OrphanPolicy {
+    /// Keep the response until a command with its id consumes it.
+    #[default]
+    Cache,
+    /// Discard the response.
+    Drop,
+    /// Pass the response to a handler, which runs on the listener task and should return quickly.
+    Callback(Arc<dyn Fn(CommandResponse) + Send + Sync>),
+}
+
+impl PartialEq for OrphanPolicy {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (OrphanPolicy::Cache, OrphanPolicy::Cache)
+            | (OrphanPolicy::Drop, OrphanPolicy::Drop) => true,
+            (OrphanPolicy::Callback(a), OrphanPolicy::Callback(b)) => Arc::ptr_eq(a, b),
+            _ => false,
+        }
+    }
+}
+
+impl Eq for OrphanPolicy {}
+
+impl std::fmt::Debug for OrphanPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            OrphanPolicy::Cache => write!(f, "Cache"),
+            OrphanPolicy::Drop => write!(f, "Drop"),
+            OrphanPolicy::Callback(_) => write!(f, "Callback(..)"),
+        }
+    }
+}
+
+mod duration_millis {
+    use super::*;
+
+    pub fn serialize<S>(duration: &Duration, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        (duration.as_millis() as u64).serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<Duration, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        Ok(Duration::from_millis(u64::deserialize(deserializer)?))
+    }
+}
+
+pub(crate) mod option_duration_millis {
+    use super::*;
+
+    pub fn serialize<S>(duration: &Option<Duration>, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        duration
+            .map(|duration| duration.as_millis() as u64)
+            .serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<Option<Duration>, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        Ok(Option::<u64>::deserialize(deserializer)?.map(Duration::from_millis))
+    }
+}
+
+impl Default for DeviceConfig {
+    fn default() -> Self {
+        Self {
+            id_range: 15..1500,
+            start_id: None,
+            history_size: 32,
+            dry_run: false,
+            max_command_len: 4096,
+            dedup_ttl: None,
+            supports_smooth: true,
+            supports_power_mode: true,
+            auto_reconnect: true,
+            reconnect_attempts: 3,
+            reconnect_backoff: Duration::from_millis(500),
+            timeout: Duration::from_secs(20),
+            orphan_responses: OrphanPolicy::Cache,
+        }
+    }
+}
+
+impl DeviceConfig {
+    fn validate(&self) -> Result<(), DeviceError> {
+        if self.start_id.is_none() && self.id_range.is_empty() {
+            return Err(DeviceError::InvalidArgument(format!(
+                "id range {:?} is empty",
+                self.id_range
+            )));
+        }
+
+        Ok(())
+    }
+}
+
+/// A builder to configure a [`Device`] before connecting to it, created with [`Device::builder`].
+///
+/// # Examples
+/// ```no_run
+/// use apyee::device::Device;
+///
+/// #[tokio::main]
+/// async fn main() -> Result<(), Box<dyn std::error::Error>> {
+///     let device = Device::builder("192.168.100.5")
+///         .port(55443)
+///         .history_size(64)
+///         .connect()
+///         .await?;
+///
+///     Ok(())
+/// }
+/// ```
+#[derive(Clone, Debug)]
+pub struct DeviceBuilder {
+    ip: String,
+    port: u16,
+    config: DeviceConfig,
+    music_mode: bool,
+}
+
+impl DeviceBuilder {
+    /// Sets the port of the device, [`DEFAULT_PORT`] by default.
+    pub fn port(mut self, port: u16) -> Self {
+        self.port = port;
+        self
+    }
+
+    /// Replaces the whole [`DeviceConfig`].
+    pub fn config(mut self, config: DeviceConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// Sets [`DeviceConfig::id_range`].
+    pub fn id_range(mut self, id_range: Range<i32>) -> Self {
+        self.config.id_range = id_range;
+        self
+    }
+
+    /// Sets [`DeviceConfig::start_id`].
+    pub fn start_id(mut self, start_id: i32) -> Self {
+        self.config.start_id = Some(start_id);
+        self
+    }
+
+    /// Sets [`DeviceConfig::history_size`].
+    pub fn history_size(mut self, history_size: usize) -> Self {
+        self.config.history_size = history_size;
+        self
+    }
+
+    /// Sets [`DeviceConfig::dry_run`].
+    pub fn dry_run(mut self, dry_run: bool) -> Self {
+        self.config.dry_run = dry_run;
+        self
+    }
+
+    /// Sets [`DeviceConfig::max_command_len`].
+    pub fn max_command_len(mut self, max_command_len: usize) -> Self {
+        self.config.max_command_len = max_command_len;
+        self
+    }
+
+    /// Sets [`DeviceConfig::dedup_ttl`].
+    pub fn dedup_ttl(mut self, dedup_ttl: Duration) -> Self {
+        self.config.dedup_ttl = Some(dedup_ttl);
+        self
+    }
+
+    /// Sets [`DeviceConfig::supports_smooth`].
+    pub fn supports_smooth(mut self, supports_smooth: bool) -> Self {
+        self.config.supports_smooth = supports_smooth;
+        self
+    }
+
+    /// Sets [`DeviceConfig::supports_power_mode`].
+    pub fn supports_power_mode(mut self, supports_power_mode: bool) -> Self {
+        self.config.supports_power_mode = supports_power_mode;
+        self
+    }
+
+    /// Sets [`DeviceConfig::timeout`].
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.config.timeout = timeout;
+        self
+    }
+
+    /// Sets [`DeviceConfig::auto_reconnect`].
+    pub fn auto_reconnect(mut self, auto_reconnect: bool) -> Self {
+        self.config.auto_reconnect = auto_reconnect;
+        self
+    }
+
+    /// Starts music mode right after connecting, listening on any local address, see [`Device::enable_music_mode`].
+    pub fn music_mode(mut self, music_mode: bool) -> Self {
+        self.music_mode = music_mode;
+        self
+    }
+
+    /// Sets [`DeviceConfig::orphan_responses`].
+    pub fn orphan_responses(mut self, orphan_responses: OrphanPolicy) -> Self {
+        self.config.orphan_responses = orphan_responses;
+        self
+    }
+
+    /// Connects to the device with the configured options, see [`Device::new_with_config`].
+    ///
+    /// # Errors
+    /// * `DeviceError::InvalidArgument` - If the port is 0 or the configuration is invalid.
+    /// * `DeviceError::Io` - If the connection fails.
+    /// * `DeviceError::Timeout` - If music mode is set and the device doesn't connect back in time.
+    pub async fn connect(self) -> DeviceResult {
+        if self.port == 0 {
+            return Err(DeviceError::InvalidArgument(String::from(
+                "port must not be 0",
+            )));
+        }
+
+        let device = Device::new_with_config(&self.ip, self.port, self.config).await?;
+        if self.music_mode {
+            device
+                .enable_music_mode(SocketAddr::from(([0, 0, 0, 0], 0)))
+                .await?;
+        }
+
+        Ok(device)
+    }
+}
+
+/// A color together with the color mode it should be set in.
+/// The device's `color_mode` property follows the method used to set a color,
+/// so setting the same visual color as RGB or HSV affects later reads.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ColorSpec {
+    /// Set the color in RGB mode through [`Method::SetRgb`].
+    Rgb(u8, u8, u8),
+    /// Set the color in HSV mode through [`Method::SetHsv`], given as hue (0 ~ 359) and saturation (0 ~ 100).
+    Hsv(i32, i32),
+}
+
+impl ColorSpec {
+    /// Creates the [`Method`] setting this color in its mode.
+    pub fn to_method(self, effect: Option<Effect>, duration: Option<i32>) -> Method {
+        match self {
+            ColorSpec::Rgb(r, g, b) => {
+                Method::SetRgb(Device::get_rgb_color(r, g, b), effect, duration)
+            }
+            ColorSpec::Hsv(hue, sat) => Method::SetHsv(hue, sat, effect, duration),
+        }
+    }
 }
 
 struct UniqueCommandId {
@@ -41,46 +400,320 @@ struct UniqueCommandId {
 }
 
 impl UniqueCommandId {
-    fn new() -> Self {
-        let rand = rand::thread_rng().gen_range(15..1500);
+    fn new(config: &DeviceConfig) -> Self {
+        let start = match config.start_id {
+            Some(start_id) => start_id,
+            None => rand::thread_rng().gen_range(config.id_range.clone()),
+        };
+
         Self {
-            id: AtomicI32::new(rand),
+            id: AtomicI32::new(start),
         }
     }
 
     fn next(&self) -> i32 {
         self.id.fetch_add(1, std::sync::atomic::Ordering::Relaxed)
     }
+
+    fn current(&self) -> i32 {
+        self.id.load(std::sync::atomic::Ordering::Relaxed)
+    }
 }
 
 struct Responses {
-    responses: HashMap<i32, CommandResponse>,
+    /// The received responses with the time they were received.
+    responses: HashMap<i32, (Instant, CommandResponse)>,
+    /// The ids of commands waiting for their response.
+    pending: HashSet<i32>,
+    orphans: OrphanPolicy,
+    /// How long a response is kept, evicted responses are removed whenever a response is added.
+    ttl: Duration,
 }
 
 impl Responses {
-    fn new() -> Self {
+    /// The maximum number of kept responses, the oldest one no command waits for is evicted when it is exceeded.
+    const CAPACITY: usize = 256;
+
+    fn new(orphans: OrphanPolicy, ttl: Duration) -> Self {
         Self {
             responses: HashMap::new(),
+            pending: HashSet::new(),
+            orphans,
+            ttl,
         }
     }
 
+    /// Marks the id as waiting for its response, so the response isn't treated as an orphan.
+    fn expect(&mut self, id: i32) {
+        self.pending.insert(id);
+    }
+
+    /// Stops waiting for the response of the id, e.g. after a timeout.
+    fn abandon(&mut self, id: i32) {
+        self.pending.remove(&id);
+        self.responses.remove(&id);
+    }
+
     fn add(&mut self, response: CommandResponse) {
-        self.responses.insert(response.id, response);
+        // expire stale responses even if no command is sent, e.g. when only listening for notifications
+        self.evict(self.ttl);
+
+        if self.pending.contains(&response.id) {
+            self.insert(response);
+            return;
+        }
+
+        match &self.orphans {
+            OrphanPolicy::Cache => self.insert(response),
+            OrphanPolicy::Drop => (),
+            OrphanPolicy::Callback(handler) => handler(response),
+        }
+    }
+
+    fn insert(&mut self, response: CommandResponse) {
+        if self.responses.len() >= Self::CAPACITY && !self.responses.contains_key(&response.id) {
+            // a command may still consume its response, only evict orphans
+            let oldest = self
+                .responses
+                .iter()
+                .filter(|(id, _)| !self.pending.contains(id))
+                .min_by_key(|(_, (received, _))| *received)
+                .map(|(id, _)| *id);
+            if let Some(oldest) = oldest {
+                self.responses.remove(&oldest);
+            }
+        }
+
+        self.responses
+            .insert(response.id, (Instant::now(), response));
+    }
+
+    /// Evicts responses received longer than `ttl` ago, which no command waits for anymore.
+    fn evict(&mut self, ttl: Duration) {
+        let pending = &self.pending;
+        self.responses
+            .retain(|id, (received, _)| pending.contains(id) || received.elapsed() < ttl);
     }
 
     fn consume(&mut self, id: i32) -> Option<CommandResponse> {
-        self.responses.remove(&id)
+        let response = self.responses.remove(&id).map(|(_, response)| response);
+        if response.is_some() {
+            self.pending.remove(&id);
+        }
+        response
+    }
+}
+
+/// Abandons the response of a command once it stops waiting for it, including when the waiting future is dropped,
+/// e.g. by [`Device::execute_method_deadline`] or a `tokio::time::timeout` around a command.
+struct PendingResponse<'a> {
+    shared: &'a Shared,
+    id: i32,
+}
+
+impl<'a> PendingResponse<'a> {
+    fn new(shared: &'a Shared, id: i32) -> Self {
+        shared.responses.lock().unwrap().expect(id);
+        Self { shared, id }
+    }
+}
+
+impl Drop for PendingResponse<'_> {
+    fn drop(&mut self) {
+        // a response arriving later is an orphan
+        self.shared.responses.lock().unwrap().abandon(self.id);
+    }
+}
+
+/// The halves of the connection to the device, shared by all clones of a [`Device`].
+#[derive(Clone)]
+struct Connection {
+    writer: Arc<Mutex<OwnedWriteHalf>>,
+    reader: Arc<Mutex<Reader>>,
+}
+
+/// The read half of the connection, together with data of a not yet completely received frame.
+struct Reader {
+    stream: OwnedReadHalf,
+    buffer: Vec<u8>,
+}
+
+struct ListenerPause {
+    paused: AtomicBool,
+    pausing: Notify,
+    resume: Notify,
+}
+
+impl ListenerPause {
+    fn new() -> Self {
+        Self {
+            paused: AtomicBool::new(false),
+            pausing: Notify::new(),
+            resume: Notify::new(),
+        }
+    }
+
+    fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::Acquire)
+    }
+
+    fn pause(&self) {
+        self.paused.store(true, Ordering::Release);
+        self.pausing.notify_one();
+    }
+
+    fn resume(&self) {
+        self.paused.store(false, Ordering::Release);
+        self.resume.notify_one();
+    }
+}
+
+/// State shared between a [`Device`] and its background listener.
+struct Shared {
+    /// Only locked briefly without awaiting, so a cancelled command can abandon its response when it is dropped.
+    responses: std::sync::Mutex<Responses>,
+    notify: Notify,
+    pause: ListenerPause,
+    properties: Mutex<HashMap<Property, String>>,
+    changes: broadcast::Sender<PropertyChange>,
+    notifications: broadcast::Sender<NotificationResult>,
+    closed: AtomicBool,
+    /// Set by [`Device::shutdown`], a shut down connection is never reconnected.
+    shut_down: AtomicBool,
+}
+
+impl Shared {
+    /// The number of property changes or notifications a subscriber can lag behind before missing some.
+    const CHANGES_CAPACITY: usize = 64;
+
+    fn new(config: &DeviceConfig) -> Self {
+        Self {
+            responses: std::sync::Mutex::new(Responses::new(
+                config.orphan_responses.clone(),
+                config.timeout,
+            )),
+            notify: Notify::new(),
+            pause: ListenerPause::new(),
+            properties: Mutex::new(HashMap::new()),
+            changes: broadcast::channel(Self::CHANGES_CAPACITY).0,
+            notifications: broadcast::channel(Self::CHANGES_CAPACITY).0,
+            closed: AtomicBool::new(false),
+            shut_down: AtomicBool::new(false),
+        }
+    }
+}
+
+/// Divides and rounds to the nearest integer, `divisor` has to be positive.
+const fn round_div(dividend: i32, divisor: i32) -> i32 {
+    if dividend >= 0 {
+        (2 * dividend + divisor) / (2 * divisor)
+    } else {
+        -((-2 * dividend + divisor) / (2 * divisor))
+    }
+}
+
+const BRIGHT_RANGE: RangeInclusive<i32> = 1..=100;
+const CT_RANGE: RangeInclusive<i32> = 1700..=6500;
+const HUE_RANGE: RangeInclusive<i32> = 0..=359;
+const SAT_RANGE: RangeInclusive<i32> = 0..=100;
+const ADJUST_RANGE: RangeInclusive<i32> = -100..=100;
+
+/// Checks that an argument is within the range the device accepts, before anything is sent.
+fn check_range(name: &str, value: i32, range: RangeInclusive<i32>) -> Result<(), DeviceError> {
+    if !range.contains(&value) {
+        return Err(DeviceError::InvalidArgument(format!(
+            "{} {} is out of range {:?}",
+            name, value, range
+        )));
+    }
+
+    Ok(())
+}
+
+fn check_scene(scene: &Scene) -> Result<(), DeviceError> {
+    match *scene {
+        Scene::Color(_, bright) => check_range("brightness", bright, BRIGHT_RANGE),
+        Scene::Hsv(hue, sat, bright) => {
+            check_range("hue", hue, HUE_RANGE)?;
+            check_range("saturation", sat, SAT_RANGE)?;
+            check_range("brightness", bright, BRIGHT_RANGE)
+        }
+        Scene::Ct(ct, bright) => {
+            check_range("color temperature", ct, CT_RANGE)?;
+            check_range("brightness", bright, BRIGHT_RANGE)
+        }
+        Scene::ColorFlow(..) => Ok(()),
+        Scene::AutoDelayOff(bright, _) => check_range("brightness", bright, BRIGHT_RANGE),
+    }
+}
+
+/// Parses a hex color like `#ff0000`, `ff0000` or the shorthand `#f00` into u8 RGB values.
+fn parse_hex_color(hex: &str) -> Result<(u8, u8, u8), DeviceError> {
+    let invalid = || DeviceError::InvalidArgument(format!("invalid hex color {:?}", hex));
+
+    let digits = hex.strip_prefix('#').unwrap_or(hex);
+    if !digits.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(invalid());
+    }
+
+    let component = |digits: &str| u8::from_str_radix(digits, 16).map_err(|_| invalid());
+    match digits.len() {
+        6 => Ok((
+            component(&digits[0..2])?,
+            component(&digits[2..4])?,
+            component(&digits[4..6])?,
+        )),
+        // every shorthand digit is doubled, e.g. f becomes ff
+        3 => Ok((
+            component(&digits[0..1])? * 0x11,
+            component(&digits[1..2])? * 0x11,
+            component(&digits[2..3])? * 0x11,
+        )),
+        _ => Err(invalid()),
+    }
+}
+
+const fn clamp_percent(value: i32) -> i32 {
+    if value < 0 {
+        0
+    } else if value > 100 {
+        100
+    } else {
+        value
     }
 }
 
+/// The state for skipping redundant sets, see [`DeviceConfig::dedup_ttl`].
+#[derive(Default)]
+struct Dedup {
+    /// The id of the most recently sent command which isn't a read.
+    last_sent: Option<i32>,
+    /// The most recent set, with the time it was sent and its command id.
+    /// Cleared whenever another command which isn't a read is sent, since it may change the state the set applied.
+    last_set: Option<(Method, Instant, i32)>,
+}
+
 /// A Yeelight device.
+///
+/// Cloning a [`Device`] is cheap and returns another handle to the same connection,
+/// so it can be moved into other tasks and send commands concurrently.
+/// The configuration is per handle, e.g. [`Device::set_timeout`] only affects the handle it is called on.
+#[derive(Clone)]
 pub struct Device {
     /// The Address of the device.
     pub address: SocketAddr,
-    responses: Arc<Mutex<Responses>>,
-    tcp_stream: Arc<Mutex<TcpStream>>,
-    command_id: UniqueCommandId,
-    notify: Arc<Notify>,
+    shared: Arc<Shared>,
+    /// The connection to the device, `None` for a dry run, see [`DeviceConfig::dry_run`].
+    connection: Option<Connection>,
+    command_id: Arc<UniqueCommandId>,
+    listener: Arc<std::sync::Mutex<Option<JoinHandle<()>>>>,
+    history: Arc<std::sync::Mutex<VecDeque<(Instant, Command)>>>,
+    config: DeviceConfig,
+    dedup: Arc<std::sync::Mutex<Dedup>>,
+    music: Arc<Mutex<Option<TcpStream>>>,
+    #[cfg(feature = "connection-registry")]
+    /// `None` for a dry run, which doesn't connect.
+    registration: Arc<Option<crate::registry::Registration>>,
 }
 
 type ExecutionResult = Result<CommandResponse, DeviceError>;
@@ -107,35 +740,133 @@ impl Device {
     /// async fn main() -> Result<(), Box<dyn std::error::Error>> {
     ///     // Create a new Device with the IP address and port of the device.
     ///     // creating the Device will also connect to it and start listening for responses.
-    ///     let mut device = Device::new_with_port("192.168.100.5", 55443).await?;
+    ///     let device = Device::new_with_port("192.168.100.5", 55443).await?;
     ///
     ///     Ok(())
     /// }
     /// ```
     pub async fn new_with_port(ip: &str, port: u16) -> DeviceResult {
-        let stream = TcpStream::connect(format!("{}:{}", ip, port)).await?;
-        let addr = stream.peer_addr()?;
-        let stream = Arc::new(Mutex::new(stream));
-        let responses = Arc::new(Mutex::new(Responses::new()));
-        let notify = Arc::new(Notify::new());
-
-        tokio::spawn(Self::listen_responses_console_error(
-            Arc::clone(&stream),
-            Arc::clone(&responses),
-            Arc::clone(&notify),
-        ));
+        Self::new_with_config(ip, port, DeviceConfig::default()).await
+    }
+
+    /// Creates a new device with ip, port and a [`DeviceConfig`].
+    /// The device will connect to the device at the given IP address and port.
+    /// If the connection fails, the function will return an error.
+    /// The device will also start listening for responses from the device.
+    /// With [`DeviceConfig::dry_run`] set, the address is only resolved and no connection is opened.
+    /// With the `connection-registry` feature, connecting while another [`Device`] in this process is already connected
+    /// to the same address is reported by `Device::is_duplicate_connection`.
+    ///
+    /// # Arguments
+    /// * `ip` - The IP address of the device.
+    /// * `port` - The port of the device.
+    /// * `config` - The configuration of the device.
+    ///
+    /// # Errors
+    /// * `DeviceError::InvalidArgument` - If the configuration is invalid, or the ip doesn't resolve to an address for a dry run.
+    /// * `DeviceError::Io` - If the connection fails.
+    ///
+    /// # Examples
+    /// ```no_run
+    /// use apyee::device::{Device, DeviceConfig};
+    ///
+    /// #[tokio::main]
+    /// async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    ///     let config = DeviceConfig {
+    ///         id_range: 10_000..20_000,
+    ///         ..Default::default()
+    ///     };
+    ///     let device = Device::new_with_config("192.168.100.5", 55443, config).await?;
+    ///
+    ///     Ok(())
+    /// }
+    /// ```
+    pub async fn new_with_config(ip: &str, port: u16, config: DeviceConfig) -> DeviceResult {
+        config.validate()?;
+
+        let shared = Arc::new(Shared::new(&config));
+
+        let (address, connection, listener) = if config.dry_run {
+            // nothing is sent, so validating commands doesn't need a connection
+            let address = tokio::net::lookup_host((ip, port))
+                .await?
+                .next()
+                .ok_or_else(|| {
+                    DeviceError::InvalidArgument(format!("{} doesn't resolve to an address", ip))
+                })?;
+
+            (address, None, None)
+        } else {
+            let stream = TcpStream::connect(format!("{}:{}", ip, port)).await?;
+            let addr = stream.peer_addr()?;
+
+            // split the stream, so the listener doesn't contend with writes
+            let (read_half, write_half) = stream.into_split();
+            let reader = Arc::new(Mutex::new(Reader {
+                stream: read_half,
+                buffer: Vec::new(),
+            }));
+
+            let listener = tokio::spawn(Self::listen_responses_console_error(
+                Arc::clone(&reader),
+                Arc::clone(&shared),
+            ));
+
+            let connection = Connection {
+                writer: Arc::new(Mutex::new(write_half)),
+                reader,
+            };
+            (addr, Some(connection), Some(listener))
+        };
+
+        #[cfg(feature = "connection-registry")]
+        let registration = connection
+            .as_ref()
+            .map(|_| crate::registry::Registration::register(address));
 
         let device = Self {
-            address: addr,
-            tcp_stream: stream,
-            responses,
-            command_id: UniqueCommandId::new(),
-            notify,
+            address,
+            shared,
+            connection,
+            command_id: Arc::new(UniqueCommandId::new(&config)),
+            listener: Arc::new(std::sync::Mutex::new(listener)),
+            history: Arc::new(std::sync::Mutex::new(VecDeque::with_capacity(
+                config.history_size,
+            ))),
+            config,
+            dedup: Arc::new(std::sync::Mutex::new(Dedup::default())),
+            music: Arc::new(Mutex::new(None)),
+            #[cfg(feature = "connection-registry")]
+            registration: Arc::new(registration),
         };
 
         Ok(device)
     }
 
+    /// Returns whether another [`Device`] in this process was already connected to the same address when this one connected.
+    /// Both devices then receive each other's responses and notifications, so their command ids may cross-talk.
+    #[cfg(feature = "connection-registry")]
+    pub fn is_duplicate_connection(&self) -> bool {
+        self.registration
+            .as_ref()
+            .as_ref()
+            .is_some_and(|registration| registration.is_duplicate())
+    }
+
+    /// Creates a [`DeviceBuilder`] for the device with the given IP address,
+    /// to configure the connection before connecting.
+    ///
+    /// # Arguments
+    /// * `ip` - The IP address of the device.
+    pub fn builder(ip: &str) -> DeviceBuilder {
+        DeviceBuilder {
+            ip: ip.to_string(),
+            port: DEFAULT_PORT,
+            config: DeviceConfig::default(),
+            music_mode: false,
+        }
+    }
+
     /// Creates a new device with ip and default port.
     /// The device will connect to the device at the given IP address and default port.
     /// If the connection fails, the function will return an error.
@@ -154,7 +885,7 @@ impl Device {
     /// async fn main() -> Result<(), Box<dyn std::error::Error>> {
     ///     // Create a new Device with the IP address of the device and the default port.
     ///     // creating the Device will also connect to it and start listening for responses.
-    ///     let mut device = Device::new("192.168.100.5").await?;
+    ///     let device = Device::new("192.168.100.5").await?;
     ///
     ///     Ok(())
     /// }
@@ -163,141 +894,2758 @@ impl Device {
         Self::new_with_port(ip, DEFAULT_PORT).await
     }
 
-    /// Converts u8 RGB values into the i32 RGB format used by the Yeelight device.\
-    /// The i32 RGB format is a 24-bit integer with the red, green, and blue values packed into a single integer.
+    /// Shuts the device down, stopping the response listener and closing the connection.
+    /// Unlike dropping the [`Device`], this only returns once the listener has stopped
+    /// and the stream has been shut down, giving a deterministic teardown.
+    /// The connection is shared with all clones of the [`Device`], so it is closed for them as well:
+    /// their pending and following commands fail with `DeviceError::ConnectionClosed`.
     ///
-    /// # Arguments
-    /// * `r` - The red value.
-    /// * `g` - The green value.
-    /// * `b` - The blue value.
-    pub const fn get_rgb_color(r: u8, g: u8, b: u8) -> i32 {
-        (r as i32) << 16 | (g as i32) << 8 | (b as i32)
+    /// # Errors
+    /// * `DeviceError::Io` - If shutting down the stream fails.
+    pub async fn shutdown(self) -> Result<(), DeviceError> {
+        self.shared.shut_down.store(true, Ordering::Release);
+
+        let listener = self.listener.lock().unwrap().take();
+        if let Some(listener) = listener {
+            listener.abort();
+            // the listener was aborted, so the only possible error is the cancellation itself
+            let _ = listener.await;
+        }
+
+        // the aborted listener doesn't mark the connection closed, wake up the commands of other handles
+        self.shared.closed.store(true, Ordering::Release);
+        self.shared.notify.notify_waiters();
+
+        let music = self.music.lock().await.take();
+        if let Some(mut music) = music {
+            music.shutdown().await?;
+        }
+
+        if let Some(connection) = &self.connection {
+            connection.writer.lock().await.shutdown().await?;
+        }
+
+        Ok(())
     }
 
-    /// Sets the color of the device, given as separate u8 RGB values.
+    /// Grants access to the underlying [`TcpStream`] of the device connection, e.g. to read socket options for diagnostics.
+    /// The write half of the connection is locked while `f` runs, so commands wait until it returns.
+    /// Returns `None` without calling `f` for a dry run, which has no connection, see [`DeviceConfig::dry_run`].
     ///
     /// # Arguments
-    /// * `r` - The red value.
-    /// * `g` - The green value.
-    /// * `b` - The blue value.
-    pub async fn set_rgb(&mut self, r: u8, g: u8, b: u8) -> ExecutionResult {
-        self.execute_method(Method::SetRgb(Self::get_rgb_color(r, g, b), None, None))
-            .await
+    /// * `f` - The function called with the stream.
+    pub async fn with_raw_stream<R>(&self, f: impl FnOnce(&TcpStream) -> R) -> Option<R> {
+        let writer = self.connection.as_ref()?.writer.lock().await;
+        Some(f(writer.as_ref()))
     }
 
-    /// Sets the background color of the device, given as separate u8 RGB values.
-    ///
-    /// # Arguments
-    /// * `r` - The red value.
-    /// * `g` - The green value.
-    /// * `b` - The blue value.
-    pub async fn set_bg_rgb(&mut self, r: u8, g: u8, b: u8) -> ExecutionResult {
-        self.execute_method(Method::BgSetRgb(Self::get_rgb_color(r, g, b), None, None))
-            .await
+    /// Returns the connection to the device, failing for a dry run, which has none.
+    fn connection(&self) -> Result<&Connection, DeviceError> {
+        self.connection.as_ref().ok_or_else(|| {
+            DeviceError::InvalidArgument(String::from("a dry run isn't connected to the device"))
+        })
     }
 
-    /// Toggles the devices power state.
-    /// If the device is on, it will be turned off.
-    /// If the device is off, it will be turned on.
-    pub async fn toggle(&mut self) -> ExecutionResult {
-        self.execute_method(Method::Toggle).await
+    /// Returns the id the next command will be sent with.
+    /// Persisting it and passing it as [`DeviceConfig::start_id`] after a restart avoids reusing ids.
+    pub fn current_id(&self) -> i32 {
+        self.command_id.current()
     }
 
-    /// Sets the power state of the device to on.
-    pub async fn power_on(&mut self) -> ExecutionResult {
-        self.execute_method(Method::SetPower(true, None, None))
-            .await
+    /// Sets the maximum time to wait for the response to a command, see [`DeviceConfig::timeout`].
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.config.timeout = timeout;
     }
 
-    /// Sets the power state of the device to off.
-    pub async fn power_off(&mut self) -> ExecutionResult {
-        self.execute_method(Method::SetPower(false, None, None))
-            .await
+    /// Sets whether a closed connection is reconnected before the next command, see [`DeviceConfig::auto_reconnect`].
+    pub fn set_auto_reconnect(&mut self, auto_reconnect: bool) {
+        self.config.auto_reconnect = auto_reconnect;
     }
 
-    /// Executes a given [`Method`] on the device by creating a new command with a unique id.
-    pub async fn execute_method(&mut self, method: Method) -> ExecutionResult {
-        let command = Command::new(self.command_id.next(), method);
+    /// Returns whether the device supports smooth transitions, see [`DeviceConfig::supports_smooth`].
+    pub fn supports_smooth(&self) -> bool {
+        self.config.supports_smooth
+    }
 
-        self.execute_command(command).await
+    /// Returns the most recently sent commands with the time they were sent, oldest first.
+    /// The number of kept commands is configured by [`DeviceConfig::history_size`].
+    /// Requests sent with [`Device::execute_json`] aren't included.
+    pub fn recent_commands(&self) -> Vec<(Instant, Command)> {
+        self.history.lock().unwrap().iter().cloned().collect()
     }
 
-    /// Executes a given [`Command`] on the device.
-    pub async fn execute_command(&mut self, command: Command) -> ExecutionResult {
-        // terminate every message with \r\n"
-        let json = serde_json::to_string(&command)?;
-        let json_command = format!("{}\r\n", json);
+    /// Pauses the background listener without closing the connection, so it stops polling the stream.
+    /// While paused, [`Device::execute_command`] reads its response from the stream itself.
+    pub fn pause_listener(&self) {
+        self.shared.pause.pause();
+    }
 
-        self.tcp_stream
-            .lock()
-            .await
-            .write_all(json_command.as_bytes())
+    /// Resumes the background listener after it was paused with [`Device::pause_listener`].
+    pub fn resume_listener(&self) {
+        self.shared.pause.resume();
+    }
+
+    /// Starts music mode: listens on `bind_addr`, tells the device to connect to it and sends all
+    /// following commands over that connection, which isn't rate limited by the device.
+    /// If `bind_addr` has an unspecified IP, the local address of the device connection is sent to the device.
+    ///
+    /// The device doesn't respond to commands in music mode, so every command succeeds with an `"ok"` result
+    /// once it is written, and reading properties or timers fails with `DeviceError::InvalidArgument`.
+    ///
+    /// # Errors
+    /// * `DeviceError::Io` - If binding the listener or accepting the connection fails.
+    /// * `DeviceError::Timeout` - If the device doesn't connect within [`DeviceConfig::timeout`].
+    pub async fn enable_music_mode(&self, bind_addr: SocketAddr) -> Result<(), DeviceError> {
+        let listener = TcpListener::bind(bind_addr).await?;
+        let mut host = listener.local_addr()?;
+        if host.ip().is_unspecified() {
+            host.set_ip(self.connection()?.writer.lock().await.local_addr()?.ip());
+        }
+
+        self.execute_method(Method::SetMusic(
+            1,
+            Some(host.ip().to_string()),
+            Some(i32::from(host.port())),
+        ))
+        .await?;
+
+        let (stream, _) = tokio::time::timeout(self.config.timeout, listener.accept()).await??;
+        *self.music.lock().await = Some(stream);
+
+        Ok(())
+    }
+
+    /// Stops music mode started with [`Device::enable_music_mode`], sending commands over the regular connection again.
+    ///
+    /// # Errors
+    /// * `DeviceError::Io` - If closing the music connection fails.
+    pub async fn disable_music_mode(&self) -> ExecutionResult {
+        let music = self.music.lock().await.take();
+        if let Some(mut music) = music {
+            music.shutdown().await?;
+        }
+
+        self.execute_method(Method::SetMusic(0, None, None)).await
+    }
+
+    /// Returns whether commands are sent over a music mode connection, see [`Device::enable_music_mode`].
+    pub async fn is_music_mode(&self) -> bool {
+        self.music.lock().await.is_some()
+    }
+
+    /// Converts u8 RGB values into the i32 RGB format used by the Yeelight device.\
+    /// The i32 RGB format is a 24-bit integer with the red, green, and blue values packed into a single integer.
+    ///
+    /// # Arguments
+    /// * `r` - The red value.
+    /// * `g` - The green value.
+    /// * `b` - The blue value.
+    pub const fn get_rgb_color(r: u8, g: u8, b: u8) -> i32 {
+        (r as i32) << 16 | (g as i32) << 8 | (b as i32)
+    }
+
+    /// Converts the i32 RGB format used by the Yeelight device back into u8 RGB values.\
+    /// This is the inverse of [`Device::get_rgb_color`]. Bits above the 24-bit color are ignored.
+    ///
+    /// # Arguments
+    /// * `color` - The packed RGB color, e.g. as reported by [`Property::Rgb`].
+    pub const fn get_rgb_components(color: i32) -> (u8, u8, u8) {
+        (
+            ((color >> 16) & 0xFF) as u8,
+            ((color >> 8) & 0xFF) as u8,
+            (color & 0xFF) as u8,
+        )
+    }
+
+    /// Converts an HSV color into the i32 RGB format used by the Yeelight device.
+    /// Out of range values are wrapped (hue) or clamped (saturation and value).
+    ///
+    /// # Arguments
+    /// * `hue` - The hue, 0 ~ 359.
+    /// * `sat` - The saturation, 0 ~ 100.
+    /// * `value` - The value, 0 ~ 100.
+    pub const fn hsv_to_rgb(hue: i32, sat: i32, value: i32) -> i32 {
+        let hue = hue.rem_euclid(360);
+        let sat = clamp_percent(sat);
+        let value = clamp_percent(value);
+
+        let max = round_div(value * 255, 100);
+        let chroma = round_div(max * sat, 100);
+        let x = round_div(chroma * (60 - ((hue % 120) - 60).abs()), 60);
+        let min = max - chroma;
+
+        let (r, g, b) = match hue / 60 {
+            0 => (chroma, x, 0),
+            1 => (x, chroma, 0),
+            2 => (0, chroma, x),
+            3 => (0, x, chroma),
+            4 => (x, 0, chroma),
+            _ => (chroma, 0, x),
+        };
+
+        Self::get_rgb_color((r + min) as u8, (g + min) as u8, (b + min) as u8)
+    }
+
+    /// Converts the i32 RGB format used by the Yeelight device into an HSV color,
+    /// with the hue in 0 ~ 359 and the saturation and value in 0 ~ 100.
+    /// This is the inverse of [`Device::hsv_to_rgb`], up to rounding.
+    ///
+    /// # Arguments
+    /// * `color` - The packed RGB color, e.g. as reported by [`Property::Rgb`].
+    pub const fn rgb_to_hsv(color: i32) -> (i32, i32, i32) {
+        let (r, g, b) = Self::get_rgb_components(color);
+        let (r, g, b) = (r as i32, g as i32, b as i32);
+
+        let max = if r > g { r } else { g };
+        let max = if max > b { max } else { b };
+        let min = if r < g { r } else { g };
+        let min = if min < b { min } else { b };
+        let delta = max - min;
+
+        let hue = if delta == 0 {
+            0
+        } else if max == r {
+            round_div(60 * (g - b), delta)
+        } else if max == g {
+            round_div(60 * (b - r), delta) + 120
+        } else {
+            round_div(60 * (r - g), delta) + 240
+        };
+        let sat = if max == 0 {
+            0
+        } else {
+            round_div(delta * 100, max)
+        };
+
+        (hue.rem_euclid(360), sat, round_div(max * 100, 255))
+    }
+
+    /// Sets the color of the device, given as separate u8 RGB values.
+    ///
+    /// # Arguments
+    /// * `r` - The red value.
+    /// * `g` - The green value.
+    /// * `b` - The blue value.
+    pub async fn set_rgb(&self, r: u8, g: u8, b: u8) -> ExecutionResult {
+        self.execute_set(Method::SetRgb(Self::get_rgb_color(r, g, b), None, None))
+            .await
+    }
+
+    /// Sets the color of the device immediately with the sudden effect, given as separate u8 RGB values.
+    /// Since it overrides any transition in progress, it can be used to snap out of a stuck smooth transition.
+    /// Unlike [`Device::set_rgb`], it is always sent, even with [`DeviceConfig::dedup_ttl`] set.
+    ///
+    /// # Arguments
+    /// * `r` - The red value.
+    /// * `g` - The green value.
+    /// * `b` - The blue value.
+    pub async fn force_color(&self, r: u8, g: u8, b: u8) -> ExecutionResult {
+        self.execute_method(Method::SetRgb(
+            Self::get_rgb_color(r, g, b),
+            Some(Effect::Sudden),
+            Some(30),
+        ))
+        .await
+    }
+
+    /// Sets the color of the device, given as a hex color like `#ff0000`, `ff0000` or the shorthand `#f00`.
+    ///
+    /// # Errors
+    /// * `DeviceError::InvalidArgument` - If `hex` isn't a valid hex color.
+    pub async fn set_rgb_hex(&self, hex: &str) -> ExecutionResult {
+        let (r, g, b) = parse_hex_color(hex)?;
+        self.set_rgb(r, g, b).await
+    }
+
+    /// Sets the background color of the device, given as a hex color, see [`Device::set_rgb_hex`].
+    ///
+    /// # Errors
+    /// * `DeviceError::InvalidArgument` - If `hex` isn't a valid hex color.
+    pub async fn set_bg_rgb_hex(&self, hex: &str) -> ExecutionResult {
+        let (r, g, b) = parse_hex_color(hex)?;
+        self.set_bg_rgb(r, g, b).await
+    }
+
+    /// Sets the color of the device, given as a CSS color name like `"tomato"`, see [`crate::colors`].
+    ///
+    /// # Errors
+    /// * `DeviceError::InvalidArgument` - If `name` isn't a known color name.
+    pub async fn set_color_named(&self, name: &str) -> ExecutionResult {
+        let (r, g, b) = crate::colors::lookup(name).ok_or_else(|| {
+            DeviceError::InvalidArgument(format!("unknown color name {:?}", name))
+        })?;
+        self.set_rgb(r, g, b).await
+    }
+
+    /// Sets the brightness of the device.
+    ///
+    /// # Arguments
+    /// * `bright` - The brightness in percent, 1 ~ 100.
+    ///
+    /// # Errors
+    /// * `DeviceError::InvalidArgument` - If `bright` is out of range.
+    pub async fn set_bright(&self, bright: i32) -> ExecutionResult {
+        check_range("brightness", bright, BRIGHT_RANGE)?;
+        self.execute_set(Method::SetBright(bright, None, None))
+            .await
+    }
+
+    /// Adjusts the brightness of the device relative to its current value.
+    ///
+    /// # Arguments
+    /// * `percentage` - The percentage to adjust by, -100 ~ 100.
+    /// * `duration` - The duration of the adjustment in milliseconds.
+    ///
+    /// # Errors
+    /// * `DeviceError::InvalidArgument` - If `percentage` is out of range.
+    pub async fn adjust_bright(&self, percentage: i32, duration: i32) -> ExecutionResult {
+        check_range("percentage", percentage, ADJUST_RANGE)?;
+        self.execute_method(Method::AdjustBright(percentage, duration))
+            .await
+    }
+
+    /// Adjusts the color temperature of the device relative to its current value.
+    ///
+    /// # Arguments
+    /// * `percentage` - The percentage to adjust by, -100 ~ 100.
+    /// * `duration` - The duration of the adjustment in milliseconds.
+    ///
+    /// # Errors
+    /// * `DeviceError::InvalidArgument` - If `percentage` is out of range.
+    pub async fn adjust_ct(&self, percentage: i32, duration: i32) -> ExecutionResult {
+        check_range("percentage", percentage, ADJUST_RANGE)?;
+        self.execute_method(Method::AdjustCt(percentage, duration))
+            .await
+    }
+
+    /// Adjusts the color of the device relative to its current value.
+    ///
+    /// # Arguments
+    /// * `percentage` - The percentage to adjust by, -100 ~ 100.
+    /// * `duration` - The duration of the adjustment in milliseconds.
+    ///
+    /// # Errors
+    /// * `DeviceError::InvalidArgument` - If `percentage` is out of range.
+    pub async fn adjust_color(&self, percentage: i32, duration: i32) -> ExecutionResult {
+        check_range("percentage", percentage, ADJUST_RANGE)?;
+        self.execute_method(Method::AdjustColor(percentage, duration))
+            .await
+    }
+
+    /// Adjusts a property of the device without knowing its current value.
+    ///
+    /// # Arguments
+    /// * `action` - The direction of the adjustment.
+    /// * `prop` - The property to adjust.
+    ///
+    /// # Errors
+    /// * `DeviceError::InvalidArgument` - If `prop` is [`AdjustProp::Color`] and `action` isn't [`AdjustAction::Circle`].
+    pub async fn set_adjust(&self, action: AdjustAction, prop: AdjustProp) -> ExecutionResult {
+        Self::check_adjust(action, prop)?;
+        self.execute_method(Method::SetAdjust(action, prop)).await
+    }
+
+    /// Adjusts a property of the background light without knowing its current value, see [`Device::set_adjust`].
+    ///
+    /// # Errors
+    /// * `DeviceError::InvalidArgument` - If `prop` is [`AdjustProp::Color`] and `action` isn't [`AdjustAction::Circle`].
+    pub async fn bg_set_adjust(&self, action: AdjustAction, prop: AdjustProp) -> ExecutionResult {
+        Self::check_adjust(action, prop)?;
+        self.execute_method(Method::BgSetAdjust(action, prop)).await
+    }
+
+    fn check_adjust(action: AdjustAction, prop: AdjustProp) -> Result<(), DeviceError> {
+        if prop == AdjustProp::Color && action != AdjustAction::Circle {
+            return Err(DeviceError::InvalidArgument(format!(
+                "color can only be adjusted with circle, not {:?}",
+                action
+            )));
+        }
+
+        Ok(())
+    }
+
+    /// Sets the device into a [`Scene`], changing all of its values at once.
+    ///
+    /// # Arguments
+    /// * `scene` - The scene to set.
+    ///
+    /// # Errors
+    /// * `DeviceError::InvalidArgument` - If a value of the scene is out of range.
+    pub async fn set_scene(&self, scene: Scene) -> ExecutionResult {
+        check_scene(&scene)?;
+        self.execute_method(Method::SetScene(scene)).await
+    }
+
+    /// Sets the background light into a [`Scene`], see [`Device::set_scene`].
+    ///
+    /// # Errors
+    /// * `DeviceError::InvalidArgument` - If a value of the scene is out of range.
+    pub async fn bg_set_scene(&self, scene: Scene) -> ExecutionResult {
+        check_scene(&scene)?;
+        self.execute_method(Method::BgSetScene(scene)).await
+    }
+
+    /// Sets the color of the device in the mode given by the [`ColorSpec`].
+    ///
+    /// # Arguments
+    /// * `color` - The color and the mode to set it in.
+    /// * `effect` - The optional effect of the transition.
+    /// * `duration` - The optional duration of the transition.
+    ///
+    /// # Errors
+    /// * `DeviceError::InvalidArgument` - If the hue or saturation of a [`ColorSpec::Hsv`] is out of range.
+    pub async fn set_color(
+        &self,
+        color: ColorSpec,
+        effect: Option<Effect>,
+        duration: Option<i32>,
+    ) -> ExecutionResult {
+        if let ColorSpec::Hsv(hue, sat) = color {
+            check_range("hue", hue, HUE_RANGE)?;
+            check_range("saturation", sat, SAT_RANGE)?;
+        }
+        self.execute_method(color.to_method(effect, duration)).await
+    }
+
+    /// Sets the background color of the device, given as separate u8 RGB values.
+    ///
+    /// # Arguments
+    /// * `r` - The red value.
+    /// * `g` - The green value.
+    /// * `b` - The blue value.
+    pub async fn set_bg_rgb(&self, r: u8, g: u8, b: u8) -> ExecutionResult {
+        self.execute_set(Method::BgSetRgb(Self::get_rgb_color(r, g, b), None, None))
+            .await
+    }
+
+    /// Sets the device to a random vivid color, returning the applied hue and saturation.
+    /// The color is picked in HSV with a high saturation, so it never ends up washed out.
+    ///
+    /// # Arguments
+    /// * `effect` - The optional effect of the transition.
+    /// * `duration` - The optional duration of the transition.
+    /// * `seed` - An optional seed to pick a reproducible color. The same seed picks the same
+    ///   color on every platform and release.
+    pub async fn random_color(
+        &self,
+        effect: Option<Effect>,
+        duration: Option<i32>,
+        seed: Option<u64>,
+    ) -> Result<(i32, i32), DeviceError> {
+        let (hue, sat) = match seed {
+            Some(seed) => Self::random_hsv(&mut ChaCha8Rng::seed_from_u64(seed)),
+            None => Self::random_hsv(&mut rand::thread_rng()),
+        };
+
+        self.execute_method(Method::SetHsv(hue, sat, effect, duration))
             .await?;
 
-        // check for multiple responses in case we get an older one with a different id
-        tokio::time::timeout(std::time::Duration::from_secs(20), async {
+        Ok((hue, sat))
+    }
+
+    fn random_hsv<R: Rng>(rng: &mut R) -> (i32, i32) {
+        (rng.gen_range(0..360), rng.gen_range(70..=100))
+    }
+
+    /// Starts a color flow on the device.
+    ///
+    /// # Arguments
+    /// * `count` - The number of state changes before the flow stops, 0 for an infinite loop.
+    /// * `action` - The action taken after the flow stops.
+    /// * `expression` - The flow to run.
+    pub async fn start_color_flow(
+        &self,
+        count: i32,
+        action: FlowAction,
+        expression: &FlowExpression,
+    ) -> ExecutionResult {
+        self.execute_method(Method::StartCf(
+            count,
+            action as i32,
+            expression.to_string(),
+        ))
+        .await
+    }
+
+    /// Stops a running color flow on the device.
+    pub async fn stop_color_flow(&self) -> ExecutionResult {
+        self.execute_method(Method::StopCf).await
+    }
+
+    /// Sets which state the device restores when it is powered on through its power supply.
+    /// No model is documented to support it, see [`Method::SetPs`], so devices without support respond with an error.
+    ///
+    /// # Errors
+    /// * `DeviceError::Device` - If the device doesn't support the setting.
+    pub async fn set_power_on_default(&self, mode: PowerOnMode) -> ExecutionResult {
+        self.execute_method(Method::SetPs(
+            PowerOnMode::KEY.to_string(),
+            mode.value().to_string(),
+        ))
+        .await
+    }
+
+    /// Sets the name of the device, which is stored on the device itself.
+    ///
+    /// # Arguments
+    /// * `name` - The new name of the device.
+    pub async fn set_name(&self, name: &str) -> ExecutionResult {
+        self.execute_method(Method::SetName(name.to_string())).await
+    }
+
+    /// Toggles the devices power state.
+    /// If the device is on, it will be turned off.
+    /// If the device is off, it will be turned on.
+    pub async fn toggle(&self) -> ExecutionResult {
+        self.execute_method(Method::Toggle).await
+    }
+
+    /// Toggles the power state of the background light.
+    pub async fn bg_toggle(&self) -> ExecutionResult {
+        self.execute_method(Method::BgToggle).await
+    }
+
+    /// Toggles the power state of the main and background light at once,
+    /// unlike [`Device::toggle`] and [`Device::bg_toggle`], which only toggle one of them.
+    /// Only devices with both a main and a background light support it.
+    pub async fn dev_toggle(&self) -> ExecutionResult {
+        self.execute_method(Method::DevToggle).await
+    }
+
+    /// Sets the power state of the device to on.
+    pub async fn power_on(&self) -> ExecutionResult {
+        self.execute_method(Method::SetPower(true, None, None, None))
+            .await
+    }
+
+    /// Saves the current state of the device, so it is restored after a power cut, and confirms the device saved it.
+    ///
+    /// # Errors
+    /// * `DeviceError::Device` - If the device responds with an error.
+    /// * `DeviceError::UnexpectedResponse` - If the device doesn't respond with `"ok"`.
+    pub async fn save_state_confirmed(&self) -> Result<(), DeviceError> {
+        let response = self.execute_method(Method::SetDefault).await?;
+
+        match response.result.as_slice() {
+            [CommandResult::Ok] => Ok(()),
+            _ => Err(DeviceError::UnexpectedResponse(response)),
+        }
+    }
+
+    /// Sets the power state of the device to on and waits until the device reports being on.
+    /// This makes sure the device is ready before sending e.g. color commands.
+    /// Waits for the property change notification the device sends, see [`Device::subscribe`].
+    /// As a fallback, e.g. if the notification is missed, the power state is polled every 500ms,
+    /// where each poll counts against the command quota. Music mode only waits for the notification.
+    ///
+    /// # Arguments
+    /// * `timeout` - The maximum time to wait for the device to report being on.
+    ///
+    /// # Errors
+    /// * `DeviceError::Timeout` - If the device doesn't report being on within `timeout`.
+    pub async fn power_on_confirmed(&self, timeout: Duration) -> Result<(), DeviceError> {
+        const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+        tokio::time::timeout(timeout, async {
+            // subscribe first, so the notification about turning on isn't missed
+            let mut changes = self.subscribe();
+            self.power_on().await?;
+
+            let mut poll = tokio::time::interval_at(Instant::now() + POLL_INTERVAL, POLL_INTERVAL);
             loop {
-                // check if we have a response for our current id
-                if let Some(response) = self.responses.lock().await.consume(command.id) {
-                    return Ok(response);
-                }
+                tokio::select! {
+                    change = changes.recv() => match change {
+                        Ok(PropertyChange { property: Property::Power, value }) if value == "on" => {
+                            return Ok(());
+                        }
+                        // other changes are ignored, and missed ones are caught up by polling
+                        _ => (),
+                    },
+                    _ = poll.tick() => {
+                        // the device doesn't answer reads in music mode
+                        if self.is_music_mode().await {
+                            continue;
+                        }
 
-                // otherwise wait for a new notification
-                tokio::time::timeout(std::time::Duration::from_secs(5), self.notify.notified())
-                    .await?;
+                        let response = self
+                            .execute_method(Method::GetProp(Property::Power))
+                            .await?;
+                        if response.result.first() == Some(&CommandResult::On) {
+                            return Ok(());
+                        }
+                    }
+                }
             }
         })
         .await?
     }
 
-    async fn listen_responses(
-        tcp_stream: Arc<Mutex<TcpStream>>,
-        responses: Arc<Mutex<Responses>>,
-        notify: Arc<Notify>,
-    ) -> Result<(), DeviceError> {
-        loop {
-            let mut buffer = [0u8; 8192];
-            match tcp_stream.lock().await.try_read(&mut buffer) {
-                Ok(0) => {
-                    // if the connection is closed, return
-                    return Ok(());
-                }
-                Ok(n) => {
-                    // parse the json
-                    let data = std::str::from_utf8(&buffer[..n])?;
-                    let entries = data.split_terminator("\r\n");
-                    for entry in entries {
-                        if let Ok(response) = serde_json::from_str::<CommandResponse>(entry) {
-                            responses.lock().await.add(response);
-                            notify.notify_one();
-                        };
-
-                        if let Ok(response) = serde_json::from_str::<NotificationResult>(entry) {
-                            // TODO: Save properties somewhere
-                        }
-                    }
-                }
-                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
-                    tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
-                    continue;
+    /// Sets the power state of the device to on, switching into the given [`PowerMode`], and sets the brightness of that mode.
+    /// E.g. [`PowerMode::NightLight`] turns a ceiling light on directly into moonlight at the given brightness.
+    ///
+    /// The mode is only sent if [`DeviceConfig::supports_power_mode`] is set. If the device replies with an error,
+    /// as devices without support for the mode parameter do, it is turned on without switching the mode instead.
+    ///
+    /// # Arguments
+    /// * `mode` - The mode to switch into.
+    /// * `bright` - The brightness in percent, 1 ~ 100.
+    /// * `effect` - The transition effect of turning on and setting the brightness.
+    /// * `duration` - The duration of the transitions in milliseconds.
+    ///
+    /// # Errors
+    /// * `DeviceError::InvalidArgument` - If `bright` is out of range.
+    pub async fn power_on_with_mode(
+        &self,
+        mode: PowerMode,
+        bright: i32,
+        effect: Effect,
+        duration: i32,
+    ) -> ExecutionResult {
+        check_range("brightness", bright, BRIGHT_RANGE)?;
+
+        let plain = Method::SetPower(true, Some(effect.clone()), Some(duration), None);
+        if self.config.supports_power_mode {
+            let with_mode =
+                Method::SetPower(true, Some(effect.clone()), Some(duration), Some(mode));
+            match self.execute_method(with_mode).await {
+                Err(DeviceError::Device { .. }) => {
+                    self.execute_method(plain).await?;
                 }
-                Err(e) => {
-                    return Err(e.into());
+                result => {
+                    result?;
                 }
             }
+        } else {
+            self.execute_method(plain).await?;
         }
+
+        self.execute_method(Method::SetBright(bright, Some(effect), Some(duration)))
+            .await
     }
 
-    async fn listen_responses_console_error(
-        tcp_stream: Arc<Mutex<TcpStream>>,
-        responses: Arc<Mutex<Responses>>,
-        notify: Arc<Notify>,
-    ) {
-        match Self::listen_responses(tcp_stream, responses, notify).await {
-            Ok(_) => (),
-            Err(e) => {
-                eprintln!("{}", e);
+    /// Sets the power state of the device to off.
+    pub async fn power_off(&self) -> ExecutionResult {
+        self.execute_method(Method::SetPower(false, None, None, None))
+            .await
+    }
+
+    /// Turns the device off after the given number of minutes, replacing a running sleep timer.
+    ///
+    /// # Arguments
+    /// * `minutes` - The delay until the device turns off.
+    pub async fn sleep_timer(&self, minutes: i32) -> ExecutionResult {
+        self.execute_method(Method::CronAdd(0, minutes)).await
+    }
+
+    /// Cancels the sleep timer set with [`Device::sleep_timer`].
+    pub async fn cancel_sleep_timer(&self) -> ExecutionResult {
+        self.execute_method(Method::CronDel(0)).await
+    }
+
+    /// Reads the running sleep timer set with [`Device::sleep_timer`], if any.
+    ///
+    /// # Errors
+    /// * `DeviceError::Json` - If the response doesn't contain a timer.
+    pub async fn get_sleep_timer(&self) -> Result<Option<CronEntry>, DeviceError> {
+        let response = self.execute_method(Method::CronGet(0)).await?;
+        Ok(CronEntry::from_response(&response)?.into_iter().next())
+    }
+
+    /// Reads the raw value of a [`Property`] as reported by the device, e.g. "50" for [`Property::Bright`].
+    /// Properties the device doesn't support are reported as an empty string.
+    ///
+    /// # Errors
+    /// * `DeviceError::UnexpectedResponse` - If the response doesn't contain a value.
+    pub async fn get_property(&self, prop: Property) -> Result<String, DeviceError> {
+        let response = self.execute_method(Method::GetProp(prop)).await?;
+
+        match response
+            .result
+            .first()
+            .and_then(CommandResult::as_str)
+            .map(str::to_string)
+        {
+            Some(value) => Ok(value),
+            None => Err(DeviceError::UnexpectedResponse(response)),
+        }
+    }
+
+    /// Reads the raw values of multiple [`Property`]s at once, see [`Device::get_property`].
+    ///
+    /// # Errors
+    /// * `DeviceError::InvalidArgument` - If `props` is empty.
+    /// * `DeviceError::UnexpectedResponse` - If the response doesn't contain exactly one value per property.
+    pub async fn get_properties(
+        &self,
+        props: &[Property],
+    ) -> Result<HashMap<Property, String>, DeviceError> {
+        if props.is_empty() {
+            return Err(DeviceError::InvalidArgument(String::from(
+                "no properties to read",
+            )));
+        }
+
+        let response = self
+            .execute_method(Method::GetProps(props.to_vec()))
+            .await?;
+        let values: Option<Vec<String>> = response
+            .result
+            .iter()
+            .map(|result| result.as_str().map(str::to_string))
+            .collect();
+        match values {
+            Some(values) if values.len() == props.len() => {
+                Ok(props.iter().cloned().zip(values).collect())
             }
+            _ => Err(DeviceError::UnexpectedResponse(response)),
+        }
+    }
+
+    /// Returns the last known raw value of a [`Property`], without querying the device.
+    /// Values are updated from property change notifications and from reading properties.
+    pub async fn cached_property(&self, prop: Property) -> Option<String> {
+        self.shared.properties.lock().await.get(&prop).cloned()
+    }
+
+    /// Subscribes to property changes the device reports on its own,
+    /// e.g. when it is toggled with the physical switch or another app.
+    pub fn subscribe(&self) -> broadcast::Receiver<PropertyChange> {
+        self.shared.changes.subscribe()
+    }
+
+    /// Subscribes to all notifications the device sends, including methods other than property changes,
+    /// e.g. ones introduced by newer firmware. See [`Device::subscribe`] for the changed properties only.
+    pub fn subscribe_notifications(&self) -> broadcast::Receiver<NotificationResult> {
+        self.shared.notifications.subscribe()
+    }
+
+    /// Returns all last known raw property values, without querying the device.
+    /// Values are updated from property change notifications and from reading properties.
+    pub async fn cached_properties(&self) -> HashMap<Property, String> {
+        self.shared.properties.lock().await.clone()
+    }
+
+    /// Reads whether the background light of the device is turned on.
+    /// Both the `"on"`/`"off"` and the `"1"`/`"0"` form some firmware reports are understood.
+    ///
+    /// # Errors
+    /// * `DeviceError::UnexpectedResponse` - If the device doesn't report an on/off state.
+    pub async fn is_bg_on(&self) -> Result<bool, DeviceError> {
+        let response = self
+            .execute_method(Method::GetProp(Property::BgPower))
+            .await?;
+
+        match response.result.first() {
+            Some(CommandResult::On) => Ok(true),
+            Some(CommandResult::Off) => Ok(false),
+            Some(CommandResult::Value(value)) if value == "1" => Ok(true),
+            Some(CommandResult::Value(value)) if value == "0" => Ok(false),
+            _ => Err(DeviceError::UnexpectedResponse(response)),
+        }
+    }
+
+    /// Executes a given [`Method`] on the device by creating a new command with a unique id.
+    pub async fn execute_method(&self, method: Method) -> ExecutionResult {
+        let command = Command::new(self.command_id.next(), method);
+
+        self.execute_command(command).await
+    }
+
+    /// Executes a method setting a value, skipping it if it was just set, see [`DeviceConfig::dedup_ttl`].
+    async fn execute_set(&self, method: Method) -> ExecutionResult {
+        let Some(ttl) = self.config.dedup_ttl else {
+            return self.execute_method(method).await;
+        };
+
+        if let Some((last, sent, id)) = &self.dedup.lock().unwrap().last_set {
+            if *last == method && sent.elapsed() < ttl {
+                return Ok(CommandResponse {
+                    id: *id,
+                    result: vec![CommandResult::Ok],
+                    error: None,
+                });
+            }
+        }
+
+        let response = self.execute_method(method.clone()).await?;
+        let mut dedup = self.dedup.lock().unwrap();
+        // a command sent by another handle in the meantime may have changed the state again
+        if dedup.last_sent == Some(response.id) {
+            dedup.last_set = Some((method, Instant::now(), response.id));
+        }
+
+        Ok(response)
+    }
+
+    /// Executes a given [`Method`] on the device, failing if it doesn't complete before the `deadline`.
+    /// Useful when a multi-step operation shares an overall time budget.
+    /// If the deadline has already passed, nothing is sent.
+    ///
+    /// # Errors
+    /// * `DeviceError::Timeout` - If the deadline passes before the response arrives.
+    pub async fn execute_method_deadline(
+        &self,
+        method: Method,
+        deadline: Instant,
+    ) -> ExecutionResult {
+        if deadline <= Instant::now() {
+            // `Elapsed` can't be constructed directly, let an expired timeout produce it
+            tokio::time::timeout_at(deadline, std::future::pending::<()>()).await?;
+        }
+
+        tokio::time::timeout_at(deadline, self.execute_method(method)).await?
+    }
+
+    /// Executes the steps of a [`Macro`] in order, waiting for each step's delay before the next one.
+    /// A failing step doesn't stop the macro, the result of every step is returned in order.
+    pub async fn run_macro(&self, r#macro: &Macro) -> Vec<ExecutionResult> {
+        let mut results = Vec::with_capacity(r#macro.steps.len());
+        for step in &r#macro.steps {
+            results.push(self.execute_method(step.method.clone()).await);
+
+            if let Some(delay) = step.delay {
+                tokio::time::sleep(delay).await;
+            }
+        }
+
+        results
+    }
+
+    /// Executes a JSON request of the form `{"method": "...", "params": [...]}` on the device and returns the response as JSON.
+    /// The method isn't checked against the known [`Method`]s, so any method the device understands can be sent.
+    /// Errors are returned as `{"error": {"message": "..."}}`, making this a one-line adapter for e.g. REST or webhook frontends.
+    ///
+    /// Since the method may not be a known [`Method`], the request isn't recorded in [`Device::recent_commands`].
+    pub async fn execute_json(&self, request: serde_json::Value) -> serde_json::Value {
+        match self.try_execute_json(request).await {
+            Ok(response) => serde_json::to_value(response).unwrap_or_else(|e| json_error(&e)),
+            Err(DeviceError::Device { code, message }) => {
+                serde_json::json!({ "error": { "code": code, "message": message } })
+            }
+            Err(e) => json_error(&e),
+        }
+    }
+
+    async fn try_execute_json(&self, request: serde_json::Value) -> ExecutionResult {
+        let request: JsonRequest = serde_json::from_value(request)?;
+        let raw = RawCommand {
+            id: self.command_id.next(),
+            method: request.method,
+            params: request.params,
+        };
+
+        let json = serde_json::to_string(&raw)?;
+        self.check_len(&json)?;
+        let is_read = matches!(raw.method.as_str(), "get_prop" | "cron_get");
+        self.check_readable(is_read).await?;
+
+        self.send_json(raw.id, json, is_read).await
+    }
+
+    /// Executes a given [`Command`] on the device.
+    ///
+    /// # Errors
+    /// * `DeviceError::InvalidArgument` - If the serialized command exceeds [`DeviceConfig::max_command_len`],
+    ///   or it reads values in music mode, see [`Device::enable_music_mode`].
+    /// * `DeviceError::Device` - If the device responds with an error.
+    /// * `DeviceError::ConnectionClosed` - If the connection is closed and [`DeviceConfig::auto_reconnect`] isn't set,
+    ///   or it is closed while waiting for the response, or it was shut down with [`Device::shutdown`].
+    pub async fn execute_command(&self, mut command: Command) -> ExecutionResult {
+        if !self.config.supports_smooth && command.method.make_sudden() {
+            command.params = command.method.get_params();
+        }
+
+        let json = serde_json::to_string(&command)?;
+        self.check_len(&json)?;
+        self.check_readable(command.method.is_read()).await?;
+        self.record(&command);
+
+        let response = self
+            .send_json(command.id, json, command.method.is_read())
+            .await?;
+        if !self.config.dry_run && !self.is_music_mode().await {
+            self.cache_properties(&command.method, &response).await;
+        }
+
+        Ok(response)
+    }
+
+    /// Stores the values of properties read with [`Method::GetProp`] or [`Method::GetProps`].
+    async fn cache_properties(&self, method: &Method, response: &CommandResponse) {
+        let props = match method {
+            Method::GetProp(prop) => std::slice::from_ref(prop),
+            Method::GetProps(props) => props.as_slice(),
+            _ => return,
+        };
+
+        let mut properties = self.shared.properties.lock().await;
+        for (prop, result) in props.iter().zip(&response.result) {
+            if let Some(value) = result.as_str() {
+                properties.insert(prop.clone(), value.to_string());
+            }
+        }
+    }
+
+    /// Fails a read in music mode before it is sent, as the device doesn't respond with the values.
+    async fn check_readable(&self, is_read: bool) -> Result<(), DeviceError> {
+        if is_read && self.is_music_mode().await {
+            return Err(DeviceError::InvalidArgument(String::from(
+                "reads aren't supported in music mode",
+            )));
+        }
+
+        Ok(())
+    }
+
+    fn check_len(&self, json: &str) -> Result<(), DeviceError> {
+        // account for the \r\n terminator
+        let len = json.len() + 2;
+        if len > self.config.max_command_len {
+            return Err(DeviceError::InvalidArgument(format!(
+                "command is {} bytes long, the maximum is {}",
+                len, self.config.max_command_len
+            )));
+        }
+
+        Ok(())
+    }
+
+    async fn send_json(&self, id: i32, json: String, is_read: bool) -> ExecutionResult {
+        // any command but a read may change the state the last set applied
+        if !is_read {
+            let mut dedup = self.dedup.lock().unwrap();
+            dedup.last_sent = Some(id);
+            dedup.last_set = None;
+        }
+
+        if self.config.dry_run {
+            return Ok(CommandResponse {
+                id,
+                result: vec![CommandResult::Ok],
+                error: None,
+            });
+        }
+
+        if !self.is_music_mode().await && self.shared.closed.load(Ordering::Acquire) {
+            if !self.config.auto_reconnect || self.shared.shut_down.load(Ordering::Acquire) {
+                return Err(DeviceError::ConnectionClosed);
+            }
+            self.reconnect().await?;
+        }
+
+        // terminate every message with \r\n"
+        let json_command = format!("{}\r\n", json);
+
+        if let Some(music) = &mut *self.music.lock().await {
+            // the device doesn't respond in music mode
+            music.write_all(json_command.as_bytes()).await?;
+            return Ok(CommandResponse {
+                id,
+                result: vec![CommandResult::Ok],
+                error: None,
+            });
+        }
+
+        // a response older than the timeout can't belong to a command still waiting
+        self.shared
+            .responses
+            .lock()
+            .unwrap()
+            .evict(self.config.timeout);
+        let pending = PendingResponse::new(&self.shared, id);
+        let response = self.write_and_wait(id, &json_command).await;
+        drop(pending);
+        let response = response?;
+
+        if let Some(CommandResponseError { code, message }) = response.error {
+            return Err(DeviceError::Device { code, message });
+        }
+
+        Ok(response)
+    }
+
+    async fn write_and_wait(&self, id: i32, json_command: &str) -> ExecutionResult {
+        self.connection()?
+            .writer
+            .lock()
+            .await
+            .write_all(json_command.as_bytes())
+            .await?;
+
+        // wake up regularly in case a notification was missed
+        let notify_timeout = self.config.timeout / 4;
+
+        // check for multiple responses in case we get an older one with a different id
+        tokio::time::timeout(self.config.timeout, async {
+            loop {
+                // register for notifications before checking, so a response arriving in between isn't missed
+                let notified = self.shared.notify.notified();
+                tokio::pin!(notified);
+                notified.as_mut().enable();
+
+                // check if we have a response for our current id
+                if let Some(response) = self.shared.responses.lock().unwrap().consume(id) {
+                    return Ok::<_, DeviceError>(response);
+                }
+
+                if self.shared.closed.load(Ordering::Acquire) {
+                    return Err(DeviceError::ConnectionClosed);
+                }
+
+                if self.shared.pause.is_paused() {
+                    // nobody else is reading, so read inline
+                    self.read_inline(notified).await?;
+                } else {
+                    // otherwise wait for a new notification
+                    let _ = tokio::time::timeout(notify_timeout, notified).await;
+                }
+            }
+        })
+        .await?
+    }
+
+    /// Connects to the device again after the connection was closed and restarts the listener.
+    /// Failed attempts are retried as configured by [`DeviceConfig::reconnect_attempts`] and [`DeviceConfig::reconnect_backoff`].
+    async fn reconnect(&self) -> Result<(), DeviceError> {
+        let connection = self.connection()?;
+        // hold the writer, so concurrent commands don't reconnect twice
+        let mut writer = connection.writer.lock().await;
+        if !self.shared.closed.load(Ordering::Acquire) {
+            // another handle reconnected while we were waiting
+            return Ok(());
+        }
+        if self.shared.shut_down.load(Ordering::Acquire) {
+            return Err(DeviceError::ConnectionClosed);
+        }
+
+        let mut backoff = self.config.reconnect_backoff;
+        let mut attempt = 1;
+        let stream = loop {
+            match TcpStream::connect(self.address).await {
+                Ok(stream) => break stream,
+                Err(e) if attempt >= self.config.reconnect_attempts => return Err(e.into()),
+                Err(_) => {
+                    tokio::time::sleep(backoff).await;
+                    backoff *= 2;
+                    attempt += 1;
+                }
+            }
+        };
+
+        // a listener paused during the close may still be running
+        if let Some(listener) = self.listener.lock().unwrap().take() {
+            listener.abort();
+        }
+
+        let (read_half, write_half) = stream.into_split();
+        *connection.reader.lock().await = Reader {
+            stream: read_half,
+            buffer: Vec::new(),
+        };
+        *writer = write_half;
+        self.shared.closed.store(false, Ordering::Release);
+
+        *self.listener.lock().unwrap() = Some(tokio::spawn(Self::listen_responses_console_error(
+            Arc::clone(&connection.reader),
+            Arc::clone(&self.shared),
+        )));
+
+        Ok(())
+    }
+
+    fn record(&self, command: &Command) {
+        if self.config.history_size == 0 {
+            return;
+        }
+
+        let mut history = self.history.lock().unwrap();
+        if history.len() == self.config.history_size {
+            history.pop_front();
+        }
+        history.push_back((Instant::now(), command.clone()));
+    }
+
+    async fn read_inline(&self, notified: Pin<&mut Notified<'_>>) -> Result<(), DeviceError> {
+        let mut reader = self.connection()?.reader.lock().await;
+        let Reader { stream, buffer } = &mut *reader;
+
+        let mut data = [0u8; 8192];
+        let n = tokio::select! {
+            read = stream.read(&mut data) => read?,
+            // the listener may have picked up the response right before it was paused
+            _ = notified => return Ok(()),
+        };
+
+        if n == 0 {
+            self.shared.closed.store(true, Ordering::Release);
+            return Err(DeviceError::ConnectionClosed);
+        }
+
+        Self::handle_read(buffer, &data[..n], &self.shared).await
+    }
+
+    /// Appends newly read data to the buffer and handles all complete frames in it.
+    /// An incomplete trailing frame is kept in the buffer until the rest of it is read.
+    async fn handle_read(
+        read_buffer: &mut Vec<u8>,
+        data: &[u8],
+        shared: &Shared,
+    ) -> Result<(), DeviceError> {
+        read_buffer.extend_from_slice(data);
+
+        if let Some(end) = read_buffer.windows(2).rposition(|w| w == b"\r\n") {
+            let frames: Vec<u8> = read_buffer.drain(..end + 2).collect();
+            Self::handle_data(&frames, shared).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn handle_data(data: &[u8], shared: &Shared) -> Result<(), DeviceError> {
+        // parse the json
+        let data = std::str::from_utf8(data)?;
+        let entries = data.split_terminator("\r\n");
+        for entry in entries {
+            // some firmware sends multiple objects without a terminator in between,
+            // so parse every object in the entry instead of the whole entry at once
+            let objects =
+                serde_json::Deserializer::from_str(entry).into_iter::<serde_json::Value>();
+            for object in objects {
+                let Ok(object) = object else {
+                    break;
+                };
+
+                if let Ok(response) = CommandResponse::deserialize(&object) {
+                    shared.responses.lock().unwrap().add(response);
+                    // several commands may be waiting for their response
+                    shared.notify.notify_waiters();
+                };
+
+                if let Ok(notification) = NotificationResult::deserialize(&object) {
+                    let mut properties = shared.properties.lock().await;
+                    for change in notification.property_changes() {
+                        properties.insert(change.property.clone(), change.value.clone());
+                        // sending only fails without subscribers, which is fine
+                        let _ = shared.changes.send(change);
+                    }
+                    drop(properties);
+
+                    // forward every notification, including methods of newer firmware
+                    let _ = shared.notifications.send(notification);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn listen_responses(
+        reader: Arc<Mutex<Reader>>,
+        shared: Arc<Shared>,
+    ) -> Result<(), DeviceError> {
+        let pause = &shared.pause;
+        loop {
+            if pause.is_paused() {
+                pause.resume.notified().await;
+                continue;
+            }
+
+            let mut reader = reader.lock().await;
+            let Reader { stream, buffer } = &mut *reader;
+
+            let mut data = [0u8; 8192];
+            let n = tokio::select! {
+                read = stream.read(&mut data) => read?,
+                // release the reader, so the paused device can read inline
+                _ = pause.pausing.notified() => continue,
+            };
+
+            if n == 0 {
+                // if the connection is closed, return
+                return Ok(());
+            }
+
+            Self::handle_read(buffer, &data[..n], &shared).await?;
+        }
+    }
+
+    async fn listen_responses_console_error(reader: Arc<Mutex<Reader>>, shared: Arc<Shared>) {
+        match Self::listen_responses(reader, Arc::clone(&shared)).await {
+            Ok(_) => (),
+            Err(e) => {
+                eprintln!("{}", e);
+            }
+        }
+
+        // the connection is unusable once the listener stops, wake up a waiting command
+        shared.closed.store(true, Ordering::Release);
+        shared.notify.notify_waiters();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::command::NotificationMethod;
+    use tokio::{
+        io::{AsyncBufReadExt, BufReader},
+        net::TcpListener,
+    };
+
+    /// Spawns a mock bulb accepting a single connection.
+    /// Every received line is passed to `respond`, whose returned lines are written back.
+    /// The returned handle resolves to all received lines once the connection is closed.
+    async fn mock_device<F>(respond: F) -> (u16, JoinHandle<Vec<String>>)
+    where
+        F: Fn(&serde_json::Value) -> Vec<String> + Send + 'static,
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let handle = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let (read, mut write) = stream.into_split();
+            let mut lines = BufReader::new(read).lines();
+            let mut received = Vec::new();
+
+            while let Ok(Some(line)) = lines.next_line().await {
+                let request: serde_json::Value = serde_json::from_str(&line).unwrap();
+                for response in respond(&request) {
+                    write
+                        .write_all(format!("{}\r\n", response).as_bytes())
+                        .await
+                        .unwrap();
+                }
+                received.push(line);
+            }
+
+            received
+        });
+
+        (port, handle)
+    }
+
+    /// Answers every command with `["ok"]`.
+    fn respond_ok(request: &serde_json::Value) -> Vec<String> {
+        vec![format!(r#"{{"id":{},"result":["ok"]}}"#, request["id"])]
+    }
+
+    #[tokio::test]
+    async fn shutdown_closes_connection() {
+        let (port, mock) = mock_device(respond_ok).await;
+        let device = Device::new_with_port("127.0.0.1", port).await.unwrap();
+
+        device.toggle().await.unwrap();
+        device.shutdown().await.unwrap();
+
+        // the mock only finishes once it reads EOF from the released socket
+        let received = tokio::time::timeout(std::time::Duration::from_secs(1), mock)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(received.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn shutdown_fails_pending_commands() {
+        let (port, _mock) = mock_device(|_| Vec::new()).await;
+        let device = Device::new_with_port("127.0.0.1", port).await.unwrap();
+
+        let clone = device.clone();
+        let pending = tokio::spawn(async move { clone.toggle().await });
+        // give the command time to be sent
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        device.shutdown().await.unwrap();
+
+        let result = tokio::time::timeout(Duration::from_secs(1), pending)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(matches!(result, Err(DeviceError::ConnectionClosed)));
+    }
+
+    #[tokio::test]
+    async fn shut_down_device_fails_commands_of_clones() {
+        let (port, _mock) = mock_device(respond_ok).await;
+        let device = Device::new_with_port("127.0.0.1", port).await.unwrap();
+
+        let clone = device.clone();
+        device.shutdown().await.unwrap();
+
+        // the connection must not be reconnected after an explicit shutdown
+        assert!(matches!(
+            clone.toggle().await,
+            Err(DeviceError::ConnectionClosed)
+        ));
+    }
+
+    #[cfg(feature = "connection-registry")]
+    #[tokio::test]
+    async fn second_connection_to_same_address_is_duplicate() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let accept = tokio::spawn(async move {
+            let first = listener.accept().await.unwrap();
+            let second = listener.accept().await.unwrap();
+            (first, second)
+        });
+
+        let first = Device::new_with_port("127.0.0.1", port).await.unwrap();
+        let second = Device::new_with_port("127.0.0.1", port).await.unwrap();
+        let _streams = accept.await.unwrap();
+
+        assert!(!first.is_duplicate_connection());
+        assert!(second.is_duplicate_connection());
+        // clones share the connection, they aren't duplicates
+        assert!(!first.clone().is_duplicate_connection());
+    }
+
+    #[tokio::test]
+    async fn cloned_device_runs_commands_concurrently() {
+        let (port, mock) = mock_device(respond_ok).await;
+        let device = Device::new_with_port("127.0.0.1", port).await.unwrap();
+
+        let clone = device.clone();
+        let task = tokio::spawn(async move { clone.toggle().await });
+        // neither command may wait for a wake-up that went to the other one
+        let (toggled, powered) = tokio::time::timeout(std::time::Duration::from_secs(1), async {
+            tokio::join!(task, device.power_on())
+        })
+        .await
+        .unwrap();
+        let toggled = toggled.unwrap().unwrap();
+        let powered = powered.unwrap();
+        assert_ne!(toggled.id, powered.id);
+
+        drop(device);
+        let received = mock.await.unwrap();
+        assert_eq!(received.len(), 2);
+    }
+
+    #[test]
+    fn command_ids_start_in_configured_range() {
+        let config = DeviceConfig {
+            id_range: 5000..5010,
+            ..Default::default()
+        };
+        let id = UniqueCommandId::new(&config).next();
+        assert!(config.id_range.contains(&id));
+    }
+
+    #[tokio::test]
+    async fn command_ids_continue_from_start_id() {
+        let (port, mock) = mock_device(respond_ok).await;
+        let config = DeviceConfig {
+            start_id: Some(4242),
+            ..Default::default()
+        };
+        let device = Device::new_with_config("127.0.0.1", port, config)
+            .await
+            .unwrap();
+
+        assert_eq!(device.current_id(), 4242);
+        device.toggle().await.unwrap();
+        assert_eq!(device.current_id(), 4243);
+        device.shutdown().await.unwrap();
+
+        let request: serde_json::Value = serde_json::from_str(&mock.await.unwrap()[0]).unwrap();
+        assert_eq!(request["id"], 4242);
+    }
+
+    #[tokio::test]
+    async fn empty_id_range_is_rejected() {
+        let config = DeviceConfig {
+            id_range: 10..10,
+            ..Default::default()
+        };
+        let result = Device::new_with_config("127.0.0.1", DEFAULT_PORT, config).await;
+        assert!(matches!(result, Err(DeviceError::InvalidArgument(_))));
+    }
+
+    #[test]
+    fn color_spec_picks_method_by_mode() {
+        assert_eq!(
+            ColorSpec::Rgb(255, 0, 0).to_method(None, None),
+            Method::SetRgb(0xFF0000, None, None)
+        );
+        assert_eq!(
+            ColorSpec::Hsv(0, 100).to_method(Some(Effect::Smooth), Some(500)),
+            Method::SetHsv(0, 100, Some(Effect::Smooth), Some(500))
+        );
+    }
+
+    #[test]
+    fn rgb_components_round_trip() {
+        assert_eq!(
+            Device::get_rgb_components(Device::get_rgb_color(255, 0, 0)),
+            (255, 0, 0)
+        );
+        assert_eq!(
+            Device::get_rgb_components(Device::get_rgb_color(18, 52, 86)),
+            (18, 52, 86)
+        );
+        assert_eq!(Device::get_rgb_components(0x7F123456), (0x12, 0x34, 0x56));
+        assert_eq!(Device::get_rgb_components(-1), (255, 255, 255));
+    }
+
+    #[test]
+    fn hsv_rgb_primaries_round_trip() {
+        for (hsv, rgb) in [
+            ((0, 100, 100), 0xFF0000),
+            ((120, 100, 100), 0x00FF00),
+            ((240, 100, 100), 0x0000FF),
+        ] {
+            assert_eq!(Device::hsv_to_rgb(hsv.0, hsv.1, hsv.2), rgb);
+            assert_eq!(Device::rgb_to_hsv(rgb), hsv);
+        }
+
+        assert_eq!(Device::hsv_to_rgb(0, 0, 100), 0xFFFFFF);
+        assert_eq!(Device::rgb_to_hsv(0), (0, 0, 0));
+        assert_eq!(Device::hsv_to_rgb(360, 100, 100), 0xFF0000);
+        assert_eq!(
+            Device::rgb_to_hsv(Device::hsv_to_rgb(300, 70, 80)),
+            (300, 70, 80)
+        );
+    }
+
+    #[test]
+    fn hex_color_parsing() {
+        assert_eq!(parse_hex_color("#ff0000").unwrap(), (255, 0, 0));
+        assert_eq!(parse_hex_color("1E90fF").unwrap(), (30, 144, 255));
+        assert_eq!(parse_hex_color("#f00").unwrap(), (255, 0, 0));
+        assert_eq!(parse_hex_color("a5c").unwrap(), (0xAA, 0x55, 0xCC));
+
+        for hex in [
+            "", "#", "#ff00", "#ff00000", "#gg0000", "ff 000", "#+f0000", "#ff00é",
+        ] {
+            assert!(
+                matches!(parse_hex_color(hex), Err(DeviceError::InvalidArgument(_))),
+                "{}",
+                hex
+            );
+        }
+    }
+
+    #[test]
+    fn config_serialization_round_trip() {
+        let config = DeviceConfig {
+            id_range: 100..200,
+            start_id: Some(150),
+            dedup_ttl: Some(Duration::from_millis(1500)),
+            ..Default::default()
+        };
+
+        let json = serde_json::to_value(&config).unwrap();
+        assert_eq!(
+            json["id_range"],
+            serde_json::json!({"start": 100, "end": 200})
+        );
+        assert_eq!(json["dedup_ttl"], 1500);
+        assert_eq!(
+            serde_json::from_value::<DeviceConfig>(json).unwrap(),
+            config
+        );
+
+        let partial: DeviceConfig = serde_json::from_str(r#"{"dry_run": true}"#).unwrap();
+        assert_eq!(
+            partial,
+            DeviceConfig {
+                dry_run: true,
+                ..Default::default()
+            }
+        );
+    }
+
+    #[test]
+    fn random_hsv_is_vivid_and_reproducible() {
+        let color = Device::random_hsv(&mut ChaCha8Rng::seed_from_u64(42));
+        assert_eq!(
+            color,
+            Device::random_hsv(&mut ChaCha8Rng::seed_from_u64(42))
+        );
+        assert!((0..360).contains(&color.0));
+        assert!((70..=100).contains(&color.1));
+    }
+
+    #[tokio::test]
+    async fn random_color_sends_chosen_color() {
+        let (port, mock) = mock_device(respond_ok).await;
+        let device = Device::new_with_port("127.0.0.1", port).await.unwrap();
+
+        let (hue, sat) = device.random_color(None, None, Some(7)).await.unwrap();
+        // the seed pins the color, so changes to the generation are noticed
+        assert_eq!((hue, sat), (50, 74));
+        device.shutdown().await.unwrap();
+
+        let received = mock.await.unwrap();
+        let request: serde_json::Value = serde_json::from_str(&received[0]).unwrap();
+        assert_eq!(request["method"], "set_hsv");
+        assert_eq!(request["params"], serde_json::json!([hue, sat]));
+    }
+
+    #[tokio::test]
+    async fn paused_listener_reads_inline() {
+        let (port, mock) = mock_device(respond_ok).await;
+        let device = Device::new_with_port("127.0.0.1", port).await.unwrap();
+
+        device.pause_listener();
+        device.toggle().await.unwrap();
+        device.resume_listener();
+        device.toggle().await.unwrap();
+        device.shutdown().await.unwrap();
+
+        assert_eq!(mock.await.unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn power_on_confirmed_waits_for_on() {
+        let polls = Arc::new(AtomicI32::new(0));
+        let mock_polls = Arc::clone(&polls);
+        let (port, _mock) = mock_device(move |request| {
+            let result = match request["method"].as_str().unwrap() {
+                "get_prop" if mock_polls.fetch_add(1, Ordering::Relaxed) == 0 => "off",
+                "get_prop" => "on",
+                _ => "ok",
+            };
+            vec![format!(
+                r#"{{"id":{},"result":["{}"]}}"#,
+                request["id"], result
+            )]
+        })
+        .await;
+        let device = Device::new_with_port("127.0.0.1", port).await.unwrap();
+
+        device
+            .power_on_confirmed(Duration::from_secs(5))
+            .await
+            .unwrap();
+        assert_eq!(polls.load(Ordering::Relaxed), 2);
+    }
+
+    #[tokio::test]
+    async fn power_on_confirmed_resolves_on_notification() {
+        let polls = Arc::new(AtomicI32::new(0));
+        let mock_polls = Arc::clone(&polls);
+        let (port, _mock) = mock_device(move |request| match request["method"].as_str().unwrap() {
+            "get_prop" => {
+                mock_polls.fetch_add(1, Ordering::Relaxed);
+                vec![format!(r#"{{"id":{},"result":["off"]}}"#, request["id"])]
+            }
+            _ => {
+                let mut lines = respond_ok(request);
+                lines.push(String::from(
+                    r#"{"method":"props","params":{"power":"on"}}"#,
+                ));
+                lines
+            }
+        })
+        .await;
+        let device = Device::new_with_port("127.0.0.1", port).await.unwrap();
+
+        device
+            .power_on_confirmed(Duration::from_secs(5))
+            .await
+            .unwrap();
+        // the notification confirmed it before the first poll
+        assert_eq!(polls.load(Ordering::Relaxed), 0);
+    }
+
+    #[tokio::test]
+    async fn run_macro_executes_steps_in_order() {
+        let (port, mock) = mock_device(respond_ok).await;
+        let device = Device::new_with_port("127.0.0.1", port).await.unwrap();
+        let r#macro = Macro::new()
+            .then(Method::SetPower(true, None, None, None))
+            .then_wait(Method::SetRgb(255, None, None), Duration::from_millis(300))
+            .then(Method::SetBright(50, None, None));
+
+        let start = tokio::time::Instant::now();
+        let results = device.run_macro(&r#macro).await;
+        assert!(start.elapsed() >= Duration::from_millis(300));
+        assert!(results.iter().all(Result::is_ok));
+        device.shutdown().await.unwrap();
+
+        let methods: Vec<String> = mock
+            .await
+            .unwrap()
+            .iter()
+            .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+            .map(|request| request["method"].as_str().unwrap().to_string())
+            .collect();
+        assert_eq!(methods, ["set_power", "set_rgb", "set_bright"]);
+    }
+
+    #[tokio::test]
+    async fn elapsed_deadline_times_out_without_sending() {
+        let (port, mock) = mock_device(respond_ok).await;
+        let device = Device::new_with_port("127.0.0.1", port).await.unwrap();
+
+        let deadline = Instant::now() - Duration::from_millis(1);
+        let result = device
+            .execute_method_deadline(Method::Toggle, deadline)
+            .await;
+        assert!(matches!(result, Err(DeviceError::Timeout(_))));
+        device.shutdown().await.unwrap();
+
+        assert!(mock.await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn recent_commands_keeps_latest() {
+        let (port, _mock) = mock_device(respond_ok).await;
+        let config = DeviceConfig {
+            history_size: 2,
+            ..Default::default()
+        };
+        let device = Device::new_with_config("127.0.0.1", port, config)
+            .await
+            .unwrap();
+
+        device.toggle().await.unwrap();
+        device.power_on().await.unwrap();
+        device.power_off().await.unwrap();
+
+        let methods: Vec<Method> = device
+            .recent_commands()
+            .into_iter()
+            .map(|(_, command)| command.method)
+            .collect();
+        assert_eq!(
+            methods,
+            [
+                Method::SetPower(true, None, None, None),
+                Method::SetPower(false, None, None, None)
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn dry_run_records_without_connecting() {
+        // nothing listens on the port, so connecting would fail
+        let port = TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let config = DeviceConfig {
+            dry_run: true,
+            ..Default::default()
+        };
+        let device = Device::new_with_config("127.0.0.1", port, config)
+            .await
+            .unwrap();
+        assert_eq!(device.address.port(), port);
+
+        let response = device.toggle().await.unwrap();
+        assert_eq!(response.result, [CommandResult::Ok]);
+        let response = device.set_bright(50).await.unwrap();
+        assert_eq!(response.result, [CommandResult::Ok]);
+        let methods: Vec<Method> = device
+            .recent_commands()
+            .into_iter()
+            .map(|(_, command)| command.method)
+            .collect();
+        assert_eq!(methods, [Method::Toggle, Method::SetBright(50, None, None)]);
+        assert!(device.with_raw_stream(|_| ()).await.is_none());
+
+        device.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn oversized_command_is_rejected() {
+        let (port, mock) = mock_device(respond_ok).await;
+        let config = DeviceConfig {
+            max_command_len: 64,
+            ..Default::default()
+        };
+        let device = Device::new_with_config("127.0.0.1", port, config)
+            .await
+            .unwrap();
+
+        let result = device
+            .execute_method(Method::GetProps(vec![Property::Power; 16]))
+            .await;
+        assert!(matches!(result, Err(DeviceError::InvalidArgument(_))));
+        device.shutdown().await.unwrap();
+
+        assert!(mock.await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn execute_json_round_trip() {
+        let (port, mock) = mock_device(respond_ok).await;
+        let device = Device::new_with_port("127.0.0.1", port).await.unwrap();
+
+        let response = device
+            .execute_json(
+                serde_json::json!({"method": "set_bright", "params": [50, "smooth", 500]}),
+            )
+            .await;
+        assert_eq!(response["result"], serde_json::json!(["ok"]));
+        assert_eq!(response["error"], serde_json::Value::Null);
+        let id = response["id"].clone();
+        assert!(device.recent_commands().is_empty());
+
+        let response = device
+            .execute_json(serde_json::json!({"params": [50]}))
+            .await;
+        assert!(response["error"]["message"].is_string());
+        device.shutdown().await.unwrap();
+
+        let received = mock.await.unwrap();
+        assert_eq!(received.len(), 1);
+        let request: serde_json::Value = serde_json::from_str(&received[0]).unwrap();
+        assert_eq!(request["id"], id);
+        assert_eq!(request["method"], "set_bright");
+        assert_eq!(request["params"], serde_json::json!([50, "smooth", 500]));
+    }
+
+    #[tokio::test]
+    async fn device_error_is_returned_as_err() {
+        let (port, _mock) = mock_device(|request| {
+            vec![format!(
+                r#"{{"id":{},"error":{{"code":-1,"message":"unsupported method"}}}}"#,
+                request["id"]
+            )]
+        })
+        .await;
+        let device = Device::new_with_port("127.0.0.1", port).await.unwrap();
+
+        match device.toggle().await {
+            Err(DeviceError::Device { code, message }) => {
+                assert_eq!(code, -1);
+                assert_eq!(message, "unsupported method");
+            }
+            other => panic!("expected a device error, got {:?}", other),
+        }
+    }
+
+    /// Spawns a mock bulb answering every command with `["ok"]`, which connects back when music mode is started.
+    /// The returned receiver yields every line received over the music connection.
+    async fn mock_music_device() -> (
+        u16,
+        JoinHandle<Vec<String>>,
+        tokio::sync::mpsc::UnboundedReceiver<String>,
+    ) {
+        let (music_tx, music_rx) = tokio::sync::mpsc::unbounded_channel();
+        let (port, mock) = mock_device(move |request| {
+            let params = request["params"].as_array().unwrap();
+            if request["method"] == "set_music" && params[0] == 1 {
+                let addr = format!("{}:{}", params[1].as_str().unwrap(), params[2]);
+                let music_tx = music_tx.clone();
+                tokio::spawn(async move {
+                    let stream = TcpStream::connect(addr).await.unwrap();
+                    let mut lines = BufReader::new(stream).lines();
+                    while let Ok(Some(line)) = lines.next_line().await {
+                        music_tx.send(line).unwrap();
+                    }
+                });
+            }
+            respond_ok(request)
+        })
+        .await;
+
+        (port, mock, music_rx)
+    }
+
+    #[tokio::test]
+    async fn music_mode_rejects_reads() {
+        let (port, _mock, mut music_rx) = mock_music_device().await;
+        let device = Device::new_with_port("127.0.0.1", port).await.unwrap();
+        device
+            .enable_music_mode("127.0.0.1:0".parse().unwrap())
+            .await
+            .unwrap();
+
+        assert!(matches!(
+            device.get_property(Property::Power).await,
+            Err(DeviceError::InvalidArgument(_))
+        ));
+        assert!(matches!(
+            device.get_properties(&[Property::Power]).await,
+            Err(DeviceError::InvalidArgument(_))
+        ));
+        assert!(matches!(
+            device.get_sleep_timer().await,
+            Err(DeviceError::InvalidArgument(_))
+        ));
+        let response = device
+            .execute_json(serde_json::json!({"method": "get_prop", "params": ["power"]}))
+            .await;
+        assert!(response["error"].is_object());
+
+        // the reads weren't written, the next line is the following command
+        device.toggle().await.unwrap();
+        let line = music_rx.recv().await.unwrap();
+        assert_eq!(
+            serde_json::from_str::<JsonRequest>(&line).unwrap().method,
+            "toggle"
+        );
+    }
+
+    #[tokio::test]
+    async fn music_mode_sends_over_music_connection() {
+        let (port, mock, mut music_rx) = mock_music_device().await;
+        let device = Device::new_with_port("127.0.0.1", port).await.unwrap();
+
+        device
+            .enable_music_mode("127.0.0.1:0".parse().unwrap())
+            .await
+            .unwrap();
+        assert!(device.is_music_mode().await);
+        device.toggle().await.unwrap();
+        let line = music_rx.recv().await.unwrap();
+        assert_eq!(
+            serde_json::from_str::<JsonRequest>(&line).unwrap().method,
+            "toggle"
+        );
+
+        device.disable_music_mode().await.unwrap();
+        assert!(!device.is_music_mode().await);
+        device.shutdown().await.unwrap();
+
+        let methods: Vec<String> = mock
+            .await
+            .unwrap()
+            .iter()
+            .map(|line| serde_json::from_str::<JsonRequest>(line).unwrap().method)
+            .collect();
+        assert_eq!(methods, ["set_music", "set_music"]);
+    }
+
+    #[tokio::test]
+    async fn save_state_confirmed_fails_on_error() {
+        let (port, _mock) = mock_device(|request| {
+            if request["method"] == "set_default" {
+                vec![format!(
+                    r#"{{"id":{},"error":{{"code":-5000,"message":"general error"}}}}"#,
+                    request["id"]
+                )]
+            } else {
+                respond_ok(request)
+            }
+        })
+        .await;
+        let device = Device::new_with_port("127.0.0.1", port).await.unwrap();
+
+        match device.save_state_confirmed().await {
+            Err(DeviceError::Device { code, .. }) => assert_eq!(code, -5000),
+            other => panic!("expected a device error, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn set_adjust_rejects_invalid_color_action() {
+        let (port, mock) = mock_device(respond_ok).await;
+        let device = Device::new_with_port("127.0.0.1", port).await.unwrap();
+
+        assert!(matches!(
+            device
+                .set_adjust(AdjustAction::Increase, AdjustProp::Color)
+                .await,
+            Err(DeviceError::InvalidArgument(_))
+        ));
+        device
+            .set_adjust(AdjustAction::Circle, AdjustProp::Color)
+            .await
+            .unwrap();
+        device.shutdown().await.unwrap();
+
+        assert_eq!(mock.await.unwrap().len(), 1);
+    }
+
+    /// Answers `set_power` with a mode with an error, like devices without support for it, and everything else with `["ok"]`.
+    fn respond_without_power_mode(request: &serde_json::Value) -> Vec<String> {
+        if request["method"] == "set_power" && request["params"].as_array().unwrap().len() == 4 {
+            vec![format!(
+                r#"{{"id":{},"error":{{"code":-1,"message":"invalid params"}}}}"#,
+                request["id"]
+            )]
+        } else {
+            respond_ok(request)
+        }
+    }
+
+    #[tokio::test]
+    async fn power_on_with_mode_sends_mode_and_brightness() {
+        let (port, mock) = mock_device(respond_ok).await;
+        let device = Device::new_with_port("127.0.0.1", port).await.unwrap();
+
+        device
+            .power_on_with_mode(PowerMode::NightLight, 20, Effect::Smooth, 300)
+            .await
+            .unwrap();
+        device.shutdown().await.unwrap();
+
+        let received: Vec<JsonRequest> = mock
+            .await
+            .unwrap()
+            .iter()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(received.len(), 2);
+        assert_eq!(received[0].method, "set_power");
+        assert_eq!(
+            received[0].params,
+            [
+                "on".into(),
+                "smooth".into(),
+                300.into(),
+                serde_json::json!(5)
+            ]
+        );
+        assert_eq!(received[1].method, "set_bright");
+        assert_eq!(
+            received[1].params,
+            [serde_json::json!(20), "smooth".into(), 300.into()]
+        );
+    }
+
+    #[tokio::test]
+    async fn power_on_with_mode_falls_back_on_error() {
+        let (port, mock) = mock_device(respond_without_power_mode).await;
+        let device = Device::new_with_port("127.0.0.1", port).await.unwrap();
+
+        device
+            .power_on_with_mode(PowerMode::NightLight, 20, Effect::Sudden, 30)
+            .await
+            .unwrap();
+        device.shutdown().await.unwrap();
+
+        let received: Vec<JsonRequest> = mock
+            .await
+            .unwrap()
+            .iter()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        let methods: Vec<&str> = received.iter().map(|r| r.method.as_str()).collect();
+        assert_eq!(methods, ["set_power", "set_power", "set_bright"]);
+        assert_eq!(received[0].params.len(), 4);
+        assert_eq!(
+            received[1].params,
+            ["on".into(), "sudden".into(), serde_json::json!(30)]
+        );
+    }
+
+    #[tokio::test]
+    async fn power_on_with_mode_skips_unsupported_mode() {
+        let (port, mock) = mock_device(respond_without_power_mode).await;
+        let config = DeviceConfig {
+            supports_power_mode: false,
+            ..Default::default()
+        };
+        let device = Device::new_with_config("127.0.0.1", port, config)
+            .await
+            .unwrap();
+
+        device
+            .power_on_with_mode(PowerMode::NightLight, 20, Effect::Sudden, 30)
+            .await
+            .unwrap();
+        device.shutdown().await.unwrap();
+
+        let received: Vec<JsonRequest> = mock
+            .await
+            .unwrap()
+            .iter()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(received.len(), 2);
+        assert_eq!(received[0].params.len(), 3);
+        assert_eq!(received[1].method, "set_bright");
+    }
+
+    #[tokio::test]
+    async fn smooth_falls_back_to_sudden_when_unsupported() {
+        let (port, mock) = mock_device(respond_ok).await;
+        let config = DeviceConfig {
+            supports_smooth: false,
+            ..Default::default()
+        };
+        let device = Device::new_with_config("127.0.0.1", port, config)
+            .await
+            .unwrap();
+        assert!(!device.supports_smooth());
+
+        device
+            .execute_method(Method::SetBright(50, Some(Effect::Smooth), Some(500)))
+            .await
+            .unwrap();
+        device.shutdown().await.unwrap();
+
+        let received = mock.await.unwrap();
+        let request: JsonRequest = serde_json::from_str(&received[0]).unwrap();
+        assert_eq!(
+            request.params,
+            [serde_json::json!(50), "sudden".into(), 500.into()]
+        );
+    }
+
+    #[tokio::test]
+    async fn raw_stream_reads_socket_options() {
+        let (port, _mock) = mock_device(respond_ok).await;
+        let device = Device::new_with_port("127.0.0.1", port).await.unwrap();
+
+        device
+            .with_raw_stream(|stream| stream.set_nodelay(true))
+            .await
+            .unwrap()
+            .unwrap();
+
+        assert!(device
+            .with_raw_stream(|stream| stream.nodelay())
+            .await
+            .unwrap()
+            .unwrap());
+        assert_eq!(
+            device
+                .with_raw_stream(|stream| stream.peer_addr())
+                .await
+                .unwrap()
+                .unwrap()
+                .port(),
+            port
+        );
+    }
+
+    /// Accepts `connections` connections one after another and answers a single command on each
+    /// with `["ok"]` before closing it.
+    async fn mock_closing_device(connections: usize) -> u16 {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        tokio::spawn(async move {
+            for _ in 0..connections {
+                let (stream, _) = listener.accept().await.unwrap();
+                let (read, mut write) = stream.into_split();
+                let mut lines = BufReader::new(read).lines();
+                if let Ok(Some(line)) = lines.next_line().await {
+                    let request: serde_json::Value = serde_json::from_str(&line).unwrap();
+                    for response in respond_ok(&request) {
+                        write
+                            .write_all(format!("{}\r\n", response).as_bytes())
+                            .await
+                            .unwrap();
+                    }
+                }
+            }
+        });
+
+        port
+    }
+
+    async fn wait_closed(device: &Device) {
+        while !device.shared.closed.load(Ordering::Acquire) {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn closed_connection_fails_without_auto_reconnect() {
+        let port = mock_closing_device(1).await;
+        let mut device = Device::new_with_port("127.0.0.1", port).await.unwrap();
+        device.set_auto_reconnect(false);
+
+        device.toggle().await.unwrap();
+        wait_closed(&device).await;
+
+        assert!(matches!(
+            device.toggle().await,
+            Err(DeviceError::ConnectionClosed)
+        ));
+    }
+
+    #[tokio::test]
+    async fn closed_connection_is_reconnected() {
+        let port = mock_closing_device(2).await;
+        let device = Device::new_with_port("127.0.0.1", port).await.unwrap();
+
+        device.toggle().await.unwrap();
+        wait_closed(&device).await;
+
+        device.toggle().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn command_times_out_after_configured_timeout() {
+        let (port, _mock) = mock_device(|_| Vec::new()).await;
+        let device = Device::builder("127.0.0.1")
+            .port(port)
+            .timeout(Duration::from_millis(200))
+            .connect()
+            .await
+            .unwrap();
+
+        let start = Instant::now();
+        assert!(matches!(
+            device.toggle().await,
+            Err(DeviceError::Timeout(_))
+        ));
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_millis(200));
+        assert!(elapsed < Duration::from_secs(2));
+    }
+
+    #[tokio::test]
+    async fn response_arriving_before_wait_is_consumed() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let mock = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            // answer right away, before the command is even sent
+            stream
+                .write_all(b"{\"id\":100,\"result\":[\"ok\"]}\r\n")
+                .await
+                .unwrap();
+            stream
+        });
+        let device = Device::builder("127.0.0.1")
+            .port(port)
+            .start_id(100)
+            .timeout(Duration::from_secs(1))
+            .connect()
+            .await
+            .unwrap();
+        let _stream = mock.await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        let response = device.toggle().await.unwrap();
+        assert_eq!(response.id, 100);
+    }
+
+    #[tokio::test]
+    async fn convenience_methods_validate_ranges() {
+        let (port, mock) = mock_device(respond_ok).await;
+        let device = Device::new_with_port("127.0.0.1", port).await.unwrap();
+
+        for bright in [0, 101] {
+            assert!(matches!(
+                device.set_bright(bright).await,
+                Err(DeviceError::InvalidArgument(_))
+            ));
+        }
+        for ct in [1699, 6501] {
+            assert!(matches!(
+                device.set_scene(Scene::Ct(ct, 50)).await,
+                Err(DeviceError::InvalidArgument(_))
+            ));
+        }
+        for (hue, sat) in [(-1, 50), (360, 50), (0, -1), (0, 101)] {
+            assert!(matches!(
+                device.set_color(ColorSpec::Hsv(hue, sat), None, None).await,
+                Err(DeviceError::InvalidArgument(_))
+            ));
+        }
+        for percentage in [-101, 101] {
+            assert!(matches!(
+                device.adjust_bright(percentage, 500).await,
+                Err(DeviceError::InvalidArgument(_))
+            ));
+        }
+
+        device.set_bright(1).await.unwrap();
+        device.set_bright(100).await.unwrap();
+        device.set_scene(Scene::Ct(1700, 1)).await.unwrap();
+        device.set_scene(Scene::Ct(6500, 100)).await.unwrap();
+        device
+            .set_color(ColorSpec::Hsv(0, 0), None, None)
+            .await
+            .unwrap();
+        device
+            .set_color(ColorSpec::Hsv(359, 100), None, None)
+            .await
+            .unwrap();
+        device.adjust_bright(-100, 500).await.unwrap();
+        device.adjust_bright(100, 500).await.unwrap();
+        device.shutdown().await.unwrap();
+
+        assert_eq!(mock.await.unwrap().len(), 8);
+    }
+
+    #[tokio::test]
+    async fn duplicate_set_within_ttl_is_skipped() {
+        let (port, mock) = mock_device(respond_ok).await;
+        let config = DeviceConfig {
+            dedup_ttl: Some(Duration::from_secs(10)),
+            ..Default::default()
+        };
+        let device = Device::new_with_config("127.0.0.1", port, config)
+            .await
+            .unwrap();
+
+        device.set_rgb(255, 0, 0).await.unwrap();
+        device.set_rgb(255, 0, 0).await.unwrap();
+        device.toggle().await.unwrap();
+        device.set_rgb(255, 0, 0).await.unwrap();
+        device.shutdown().await.unwrap();
+
+        let methods: Vec<String> = mock
+            .await
+            .unwrap()
+            .iter()
+            .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+            .map(|request| request["method"].as_str().unwrap().to_string())
+            .collect();
+        assert_eq!(methods, ["set_rgb", "toggle", "set_rgb"]);
+    }
+
+    #[tokio::test]
+    async fn duplicate_set_after_read_is_skipped() {
+        let (port, mock) = mock_device(respond_ok).await;
+        let config = DeviceConfig {
+            dedup_ttl: Some(Duration::from_secs(10)),
+            ..Default::default()
+        };
+        let device = Device::new_with_config("127.0.0.1", port, config)
+            .await
+            .unwrap();
+
+        device.set_bright(50).await.unwrap();
+        device.get_property(Property::Bright).await.unwrap();
+        device.set_bright(50).await.unwrap();
+        device.shutdown().await.unwrap();
+
+        let methods: Vec<String> = mock
+            .await
+            .unwrap()
+            .iter()
+            .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+            .map(|request| request["method"].as_str().unwrap().to_string())
+            .collect();
+        assert_eq!(methods, ["set_bright", "get_prop"]);
+    }
+
+    #[tokio::test]
+    async fn set_overtaken_by_concurrent_command_is_not_skipped() {
+        // hold back the response to the first set until the toggle sent after it is answered
+        let held = std::sync::Mutex::new(None);
+        let first = AtomicBool::new(true);
+        let (port, mock) = mock_device(move |request| {
+            if request["method"] == "set_rgb" && first.swap(false, Ordering::SeqCst) {
+                *held.lock().unwrap() = Some(request["id"].clone());
+                return Vec::new();
+            }
+
+            let mut lines = respond_ok(request);
+            if let Some(id) = held.lock().unwrap().take() {
+                lines.push(format!(r#"{{"id":{},"result":["ok"]}}"#, id));
+            }
+            lines
+        })
+        .await;
+        let config = DeviceConfig {
+            dedup_ttl: Some(Duration::from_secs(10)),
+            ..Default::default()
+        };
+        let device = Device::new_with_config("127.0.0.1", port, config)
+            .await
+            .unwrap();
+
+        let clone = device.clone();
+        let set = tokio::spawn(async move { clone.set_rgb(255, 0, 0).await });
+        // give the set time to be sent
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        device.toggle().await.unwrap();
+        set.await.unwrap().unwrap();
+        // the toggle was sent after the set, so the set has to be sent again
+        device.set_rgb(255, 0, 0).await.unwrap();
+        device.shutdown().await.unwrap();
+
+        let methods: Vec<String> = mock
+            .await
+            .unwrap()
+            .iter()
+            .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+            .map(|request| request["method"].as_str().unwrap().to_string())
+            .collect();
+        assert_eq!(methods, ["set_rgb", "toggle", "set_rgb"]);
+    }
+
+    #[tokio::test]
+    async fn concatenated_objects_are_parsed() {
+        let shared = Shared::new(&DeviceConfig::default());
+        let data = concat!(
+            r#"{"id":1,"result":["ok"]}{"method":"props","params":{"power":"on"}}"#,
+            r#"{"id":2,"result":["on"]}"#,
+            "\r\n"
+        );
+
+        Device::handle_data(data.as_bytes(), &shared).await.unwrap();
+
+        let mut responses = shared.responses.lock().unwrap();
+        assert_eq!(responses.consume(1).unwrap().result, [CommandResult::Ok]);
+        assert_eq!(responses.consume(2).unwrap().result, [CommandResult::On]);
+    }
+
+    #[tokio::test]
+    async fn response_with_large_id_is_routed() {
+        let shared = Shared::new(&DeviceConfig::default());
+        let data = format!("{{\"id\":{},\"result\":[\"ok\"]}}\r\n", i32::MAX);
+
+        Device::handle_data(data.as_bytes(), &shared).await.unwrap();
+
+        let mut responses = shared.responses.lock().unwrap();
+        let response = responses.consume(i32::MAX).unwrap();
+        assert_eq!(response.id, i32::MAX);
+        assert_eq!(response.result, [CommandResult::Ok]);
+    }
+
+    #[tokio::test]
+    async fn unknown_notification_method_is_not_dropped() {
+        let shared = Shared::new(&DeviceConfig::default());
+        let mut changes = shared.changes.subscribe();
+        let mut notifications = shared.notifications.subscribe();
+        let data = concat!(
+            r#"{"method":"future_props","params":{"power":"on"}}"#,
+            "\r\n"
+        );
+
+        Device::handle_data(data.as_bytes(), &shared).await.unwrap();
+
+        // it is forwarded, but not applied as a property change
+        let notification = notifications.try_recv().unwrap();
+        assert_eq!(
+            notification.method,
+            NotificationMethod::Other(String::from("future_props"))
+        );
+        assert!(changes.try_recv().is_err());
+        assert!(shared.properties.lock().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn unknown_notification_keys_keep_known_properties() {
+        let shared = Shared::new(&DeviceConfig::default());
+        let mut changes = shared.changes.subscribe();
+        let data = concat!(
+            r#"{"method":"props","params":{"main_power":"on","power":"on","not_a_prop":5}}"#,
+            "\r\n"
+        );
+
+        Device::handle_data(data.as_bytes(), &shared).await.unwrap();
+
+        let change = changes.try_recv().unwrap();
+        assert_eq!(change.property, Property::Power);
+        assert_eq!(change.value, "on");
+        assert!(changes.try_recv().is_err());
+        assert_eq!(
+            *shared.properties.lock().await,
+            HashMap::from([(Property::Power, String::from("on"))])
+        );
+    }
+
+    const ORPHAN: &str = "{\"id\":7,\"result\":[\"ok\"]}\r\n";
+
+    #[tokio::test]
+    async fn orphan_response_is_cached() {
+        let shared = Shared::new(&DeviceConfig::default());
+
+        Device::handle_data(ORPHAN.as_bytes(), &shared)
+            .await
+            .unwrap();
+
+        assert!(shared.responses.lock().unwrap().consume(7).is_some());
+    }
+
+    #[tokio::test]
+    async fn orphan_response_is_dropped() {
+        let shared = Shared::new(&DeviceConfig {
+            orphan_responses: OrphanPolicy::Drop,
+            ..Default::default()
+        });
+        shared.responses.lock().unwrap().expect(8);
+
+        Device::handle_data(ORPHAN.as_bytes(), &shared)
+            .await
+            .unwrap();
+        Device::handle_data(b"{\"id\":8,\"result\":[\"ok\"]}\r\n", &shared)
+            .await
+            .unwrap();
+
+        let mut responses = shared.responses.lock().unwrap();
+        assert!(responses.consume(7).is_none());
+        assert!(responses.consume(8).is_some());
+    }
+
+    #[tokio::test]
+    async fn orphan_response_is_passed_to_callback() {
+        let orphans = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let handler_orphans = Arc::clone(&orphans);
+        let shared = Shared::new(&DeviceConfig {
+            orphan_responses: OrphanPolicy::Callback(Arc::new(move |response| {
+                handler_orphans.lock().unwrap().push(response.id);
+            })),
+            ..Default::default()
+        });
+
+        Device::handle_data(ORPHAN.as_bytes(), &shared)
+            .await
+            .unwrap();
+
+        assert_eq!(*orphans.lock().unwrap(), [7]);
+        assert!(shared.responses.lock().unwrap().consume(7).is_none());
+    }
+
+    #[tokio::test]
+    async fn cancelled_command_abandons_its_response() {
+        let (port, _mock) = mock_device(|_| Vec::new()).await;
+        let device = Device::new_with_config(
+            "127.0.0.1",
+            port,
+            DeviceConfig {
+                orphan_responses: OrphanPolicy::Drop,
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap();
+
+        // the command is dropped while it waits for its response
+        assert!(
+            tokio::time::timeout(Duration::from_millis(50), device.toggle())
+                .await
+                .is_err()
+        );
+        let id = device.current_id() - 1;
+
+        let data = format!("{{\"id\":{},\"result\":[\"ok\"]}}\r\n", id);
+        Device::handle_data(data.as_bytes(), &device.shared)
+            .await
+            .unwrap();
+
+        let responses = device.shared.responses.lock().unwrap();
+        assert!(responses.pending.is_empty());
+        assert!(responses.responses.is_empty());
+    }
+
+    #[tokio::test]
+    async fn orphan_responses_are_bounded() {
+        let shared = Shared::new(&DeviceConfig::default());
+        for id in 0..300 {
+            let data = format!("{{\"id\":{},\"result\":[\"ok\"]}}\r\n", id);
+            Device::handle_data(data.as_bytes(), &shared).await.unwrap();
+        }
+
+        let mut responses = shared.responses.lock().unwrap();
+        assert_eq!(responses.responses.len(), Responses::CAPACITY);
+        assert!(responses.consume(0).is_none());
+        assert!(responses.consume(299).is_some());
+    }
+
+    #[tokio::test]
+    async fn pending_response_is_not_evicted_by_orphans() {
+        let shared = Shared::new(&DeviceConfig::default());
+        shared.responses.lock().unwrap().expect(0);
+        for id in 0..300 {
+            let data = format!("{{\"id\":{},\"result\":[\"ok\"]}}\r\n", id);
+            Device::handle_data(data.as_bytes(), &shared).await.unwrap();
+        }
+
+        let mut responses = shared.responses.lock().unwrap();
+        assert_eq!(responses.responses.len(), Responses::CAPACITY);
+        assert!(responses.consume(0).is_some());
+        assert!(responses.consume(1).is_none());
+    }
+
+    #[tokio::test]
+    async fn stale_responses_are_evicted_when_adding() {
+        let shared = Shared::new(&DeviceConfig {
+            timeout: Duration::from_millis(10),
+            ..Default::default()
+        });
+        shared.responses.lock().unwrap().expect(9);
+        Device::handle_data(ORPHAN.as_bytes(), &shared)
+            .await
+            .unwrap();
+        Device::handle_data(b"{\"id\":9,\"result\":[\"ok\"]}\r\n", &shared)
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        Device::handle_data(b"{\"id\":8,\"result\":[\"ok\"]}\r\n", &shared)
+            .await
+            .unwrap();
+
+        let mut responses = shared.responses.lock().unwrap();
+        assert!(responses.consume(7).is_none());
+        // a command still waits for it
+        assert!(responses.consume(9).is_some());
+        assert!(responses.consume(8).is_some());
+    }
+
+    #[tokio::test]
+    async fn stale_responses_are_evicted() {
+        let shared = Shared::new(&DeviceConfig::default());
+        Device::handle_data(ORPHAN.as_bytes(), &shared)
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        Device::handle_data(b"{\"id\":8,\"result\":[\"ok\"]}\r\n", &shared)
+            .await
+            .unwrap();
+
+        let mut responses = shared.responses.lock().unwrap();
+        responses.evict(Duration::from_millis(10));
+        assert!(responses.consume(7).is_none());
+        assert!(responses.consume(8).is_some());
+    }
+
+    #[tokio::test]
+    async fn builder_connects_with_options() {
+        let (port, mock) = mock_device(respond_ok).await;
+        let device = Device::builder("127.0.0.1")
+            .port(port)
+            .start_id(100)
+            .history_size(1)
+            .max_command_len(256)
+            .dedup_ttl(Duration::from_secs(1))
+            .timeout(Duration::from_secs(5))
+            .auto_reconnect(false)
+            .connect()
+            .await
+            .unwrap();
+
+        device.set_bright(50).await.unwrap();
+        device.set_bright(50).await.unwrap();
+        device.toggle().await.unwrap();
+        assert_eq!(device.recent_commands().len(), 1);
+        device.shutdown().await.unwrap();
+
+        let ids: Vec<serde_json::Value> = mock
+            .await
+            .unwrap()
+            .iter()
+            .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap()["id"].clone())
+            .collect();
+        assert_eq!(ids, [100, 101]);
+    }
+
+    #[tokio::test]
+    async fn builder_enables_music_mode() {
+        let (port, mock, mut music_rx) = mock_music_device().await;
+        let device = Device::builder("127.0.0.1")
+            .port(port)
+            .music_mode(true)
+            .connect()
+            .await
+            .unwrap();
+        assert!(device.is_music_mode().await);
+
+        device.toggle().await.unwrap();
+        let line = music_rx.recv().await.unwrap();
+        assert_eq!(
+            serde_json::from_str::<JsonRequest>(&line).unwrap().method,
+            "toggle"
+        );
+        device.shutdown().await.unwrap();
+
+        // only starting music mode was sent over the regular connection
+        let received = mock.await.unwrap();
+        assert_eq!(received.len(), 1);
+        let request: serde_json::Value = serde_json::from_str(&received[0]).unwrap();
+        assert_eq!(request["method"], "set_music");
+        assert_eq!(request["params"][0], 1);
+        assert_eq!(request["params"][1], "127.0.0.1");
+    }
+
+    #[tokio::test]
+    async fn builder_rejects_port_zero() {
+        assert!(matches!(
+            Device::builder("127.0.0.1").port(0).connect().await,
+            Err(DeviceError::InvalidArgument(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn get_property_returns_raw_value() {
+        let (port, _mock) = mock_device(|request| {
+            let value = match request["params"][0].as_str().unwrap() {
+                "bright" => "50",
+                "power" => "on",
+                _ => "",
+            };
+            vec![format!(
+                r#"{{"id":{},"result":["{}"]}}"#,
+                request["id"], value
+            )]
+        })
+        .await;
+        let device = Device::new_with_port("127.0.0.1", port).await.unwrap();
+
+        assert_eq!(device.get_property(Property::Bright).await.unwrap(), "50");
+        assert_eq!(device.get_property(Property::Power).await.unwrap(), "on");
+        assert_eq!(device.get_property(Property::BgPower).await.unwrap(), "");
+    }
+
+    #[tokio::test]
+    async fn get_properties_pairs_values_by_index() {
+        let (port, _mock) = mock_device(|request| {
+            let values: Vec<&str> = request["params"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|prop| match prop.as_str().unwrap() {
+                    "power" => "on",
+                    "bright" => "50",
+                    _ => "",
+                })
+                .collect();
+            vec![serde_json::json!({"id": request["id"], "result": values}).to_string()]
+        })
+        .await;
+        let device = Device::new_with_port("127.0.0.1", port).await.unwrap();
+
+        let props = device
+            .get_properties(&[Property::Bright, Property::Power])
+            .await
+            .unwrap();
+        assert_eq!(props.len(), 2);
+        assert_eq!(props[&Property::Bright], "50");
+        assert_eq!(props[&Property::Power], "on");
+    }
+
+    #[tokio::test]
+    async fn get_properties_rejects_value_count_mismatch() {
+        let (port, _mock) =
+            mock_device(|request| vec![format!(r#"{{"id":{},"result":["on"]}}"#, request["id"])])
+                .await;
+        let device = Device::new_with_port("127.0.0.1", port).await.unwrap();
+
+        assert!(matches!(
+            device
+                .get_properties(&[Property::Power, Property::Bright])
+                .await,
+            Err(DeviceError::UnexpectedResponse(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn notifications_update_cached_properties() {
+        let (port, _mock) = mock_device(|request| {
+            vec![
+                r#"{"method":"props","params":{"power":"on","bright":10}}"#.to_string(),
+                format!(r#"{{"id":{},"result":["ok"]}}"#, request["id"]),
+            ]
+        })
+        .await;
+        let device = Device::new_with_port("127.0.0.1", port).await.unwrap();
+        assert_eq!(device.cached_property(Property::Power).await, None);
+
+        device.toggle().await.unwrap();
+
+        assert_eq!(
+            device.cached_property(Property::Power).await.as_deref(),
+            Some("on")
+        );
+        assert_eq!(
+            device.cached_property(Property::Bright).await.as_deref(),
+            Some("10")
+        );
+    }
+
+    #[tokio::test]
+    async fn subscribers_receive_property_changes() {
+        let (port, _mock) = mock_device(|request| {
+            vec![
+                format!(r#"{{"id":{},"result":["ok"]}}"#, request["id"]),
+                r#"{"method":"props","params":{"power":"off"}}"#.to_string(),
+            ]
+        })
+        .await;
+        let device = Device::new_with_port("127.0.0.1", port).await.unwrap();
+        let mut changes = device.subscribe();
+
+        device.toggle().await.unwrap();
+
+        let change = tokio::time::timeout(Duration::from_secs(1), changes.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            change,
+            PropertyChange {
+                property: Property::Power,
+                value: "off".to_string(),
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn reading_properties_updates_cache() {
+        let (port, _mock) =
+            mock_device(|request| vec![format!(r#"{{"id":{},"result":["80"]}}"#, request["id"])])
+                .await;
+        let device = Device::new_with_port("127.0.0.1", port).await.unwrap();
+
+        device.get_property(Property::Bright).await.unwrap();
+
+        let cached = device.cached_properties().await;
+        assert_eq!(cached.len(), 1);
+        assert_eq!(cached[&Property::Bright], "80");
+    }
+
+    #[tokio::test]
+    async fn response_split_across_reads_is_parsed() {
+        let shared = Shared::new(&DeviceConfig::default());
+        let mut read_buffer = Vec::new();
+
+        Device::handle_read(&mut read_buffer, br#"{"id":1,"result":["ok"]}"#, &shared)
+            .await
+            .unwrap();
+        Device::handle_read(&mut read_buffer, br#"{"id":2,"res"#, &shared)
+            .await
+            .unwrap();
+        assert!(shared.responses.lock().unwrap().consume(2).is_none());
+
+        Device::handle_read(&mut read_buffer, b"ult\":[\"on\"]}\r\n", &shared)
+            .await
+            .unwrap();
+
+        assert!(read_buffer.is_empty());
+        let mut responses = shared.responses.lock().unwrap();
+        assert_eq!(responses.consume(1).unwrap().result, [CommandResult::Ok]);
+        assert_eq!(responses.consume(2).unwrap().result, [CommandResult::On]);
+    }
+
+    #[tokio::test]
+    async fn force_color_uses_sudden_effect() {
+        let (port, mock) = mock_device(respond_ok).await;
+        let device = Device::new_with_port("127.0.0.1", port).await.unwrap();
+
+        device.force_color(255, 0, 0).await.unwrap();
+        device.shutdown().await.unwrap();
+
+        let request: serde_json::Value = serde_json::from_str(&mock.await.unwrap()[0]).unwrap();
+        assert_eq!(request["method"], "set_rgb");
+        assert_eq!(
+            request["params"],
+            serde_json::json!([0xFF0000, "sudden", 30])
+        );
+    }
+
+    #[tokio::test]
+    async fn is_bg_on_parses_power_state() {
+        // the states as sent in the response, including the numeric forms as strings and numbers
+        for (state, expected) in [
+            (r#""on""#, true),
+            (r#""off""#, false),
+            (r#""1""#, true),
+            (r#""0""#, false),
+            ("1", true),
+            ("0", false),
+        ] {
+            let (port, _mock) = mock_device(move |request| {
+                assert_eq!(request["method"], "get_prop");
+                assert_eq!(request["params"], serde_json::json!(["bg_power"]));
+                vec![format!(
+                    r#"{{"id":{},"result":[{}]}}"#,
+                    request["id"], state
+                )]
+            })
+            .await;
+            let device = Device::new_with_port("127.0.0.1", port).await.unwrap();
+
+            assert_eq!(device.is_bg_on().await.unwrap(), expected);
         }
     }
 }
@@ -0,0 +1,116 @@
+use std::fmt::{self, Display};
+
+/// The mode of a [`FlowTuple`].
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum FlowMode {
+    /// Change to an RGB color.
+    Color = 1,
+    /// Change to a color temperature.
+    ColorTemperature = 2,
+    /// Sleep for the duration, keeping the current state.
+    Sleep = 7,
+}
+
+/// The action taken after a color flow stops, passed to [`crate::device::Device::start_color_flow`].
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum FlowAction {
+    /// Recover to the state before the color flow started.
+    Recover = 0,
+    /// Stay at the state when the flow stopped.
+    Stay = 1,
+    /// Turn off the device after the flow stopped.
+    TurnOff = 2,
+}
+
+/// A single state change of a [`FlowExpression`].
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct FlowTuple {
+    /// The duration of the transition or sleep in milliseconds, at least 50.
+    pub duration: i32,
+    /// The mode of the state change.
+    pub mode: FlowMode,
+    /// The RGB color for [`FlowMode::Color`], the color temperature for [`FlowMode::ColorTemperature`], ignored for [`FlowMode::Sleep`].
+    pub value: i32,
+    /// The brightness in percent, 1 ~ 100, or -1 to keep the brightness. Ignored for [`FlowMode::Sleep`].
+    pub brightness: i32,
+}
+
+impl FlowTuple {
+    /// Creates a tuple changing to an RGB color, e.g. from [`crate::device::Device::get_rgb_color`].
+    pub const fn color(duration: i32, rgb: i32, brightness: i32) -> Self {
+        Self {
+            duration,
+            mode: FlowMode::Color,
+            value: rgb,
+            brightness,
+        }
+    }
+
+    /// Creates a tuple changing to a color temperature, 1700 ~ 6500 (k).
+    pub const fn color_temperature(duration: i32, ct: i32, brightness: i32) -> Self {
+        Self {
+            duration,
+            mode: FlowMode::ColorTemperature,
+            value: ct,
+            brightness,
+        }
+    }
+
+    /// Creates a tuple keeping the current state for the duration.
+    pub const fn sleep(duration: i32) -> Self {
+        Self {
+            duration,
+            mode: FlowMode::Sleep,
+            value: 0,
+            brightness: 0,
+        }
+    }
+}
+
+/// A sequence of [`FlowTuple`]s describing a color flow, sent as the comma separated flow expression the device expects.
+///
+/// # Examples
+/// ```
+/// use apyee::flow::{FlowExpression, FlowTuple};
+///
+/// let expression = FlowExpression::new()
+///     .then(FlowTuple::color_temperature(1000, 2700, 100))
+///     .then(FlowTuple::color(500, 0xFF0000, 10));
+///
+/// assert_eq!(expression.to_string(), "1000,2,2700,100,500,1,16711680,10");
+/// ```
+#[derive(Clone, PartialEq, Eq, Debug, Default)]
+pub struct FlowExpression {
+    /// The state changes of the flow, in order.
+    pub tuples: Vec<FlowTuple>,
+}
+
+impl FlowExpression {
+    /// Creates a new, empty flow expression.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Appends a [`FlowTuple`] to the flow.
+    pub fn then(mut self, tuple: FlowTuple) -> Self {
+        self.tuples.push(tuple);
+        self
+    }
+}
+
+impl Display for FlowExpression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, tuple) in self.tuples.iter().enumerate() {
+            if i > 0 {
+                write!(f, ",")?;
+            }
+            write!(
+                f,
+                "{},{},{},{}",
+                tuple.duration, tuple.mode as i32, tuple.value, tuple.brightness
+            )?;
+        }
+
+        Ok(())
+    }
+}
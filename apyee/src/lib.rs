@@ -14,7 +14,7 @@
 //! async fn main() -> Result<(), Box<dyn std::error::Error>> {
 //!     // Create a new Device with the IP address of the device and the default port.
 //!     // creating the Device will also connect to it and start listening for responses.
-//!     let mut device = Device::new("192.168.100.5").await?;
+//!     let device = Device::new("192.168.100.5").await?;
 //!
 //!     // Send a command through a convenience method and toggle its power state.
 //!     device.toggle().await?;
@@ -29,28 +29,41 @@
 //! }
 //! ```
 
+/// Named colors which can be set with [`crate::device::Device::set_color_named`].
+pub mod colors;
 /// Commands and their responses which are sent and received from the [`crate::device::Device`].
 pub mod command;
 /// The [`crate::device::Device`] itself, used to interact with the Yeelight device.
 pub mod device;
+/// The [`crate::flow::FlowExpression`]s describing color flows on the Yeelight device.
+pub mod flow;
 /// The [`crate::method::Method`]s which are called on the Yeelight device.
 pub mod method;
 /// The [`crate::property::Property`]s which are queried from the Yeelight device.
 pub mod property;
+#[cfg(feature = "connection-registry")]
+mod registry;
 
 #[cfg(test)]
 mod tests {
     use crate::{
-        command::{self, CommandResponse, CommandResult},
+        colors,
+        command::{self, CommandResponse, CommandResult, NotificationMethod, NotificationResult},
         device::Device,
-        method::{Effect, Method},
+        flow::{FlowExpression, FlowTuple},
+        method::{
+            AdjustAction, AdjustProp, Effect, Macro, Method, PowerMode, PowerOnMode, Scene,
+            Transition,
+        },
         property::Property,
     };
 
     #[test]
     fn it_works() {
-        let command =
-            command::Command::new(0, Method::SetPower(true, Some(Effect::Smooth), Some(500)));
+        let command = command::Command::new(
+            0,
+            Method::SetPower(true, Some(Effect::Smooth), Some(500), None),
+        );
         let json = serde_json::to_string(&command).unwrap();
         println!("{}", json);
     }
@@ -85,6 +98,523 @@ mod tests {
         );
     }
 
+    #[test]
+    fn command_set_power_with_mode_serialization() {
+        let command = command::Command::new(
+            0,
+            Method::SetPower(
+                true,
+                Some(Effect::Smooth),
+                Some(500),
+                Some(PowerMode::NightLight),
+            ),
+        );
+        let json = serde_json::to_string(&command).unwrap();
+        assert_eq!(
+            json,
+            r#"{"id":0,"method":"set_power","params":["on","smooth",500,5]}"#
+        );
+    }
+
+    #[test]
+    fn command_set_power_with_mode_without_transition_serialization() {
+        let command =
+            command::Command::new(0, Method::SetPower(true, None, None, Some(PowerMode::Ct)));
+        let json = serde_json::to_string(&command).unwrap();
+        assert_eq!(
+            json,
+            r#"{"id":0,"method":"set_power","params":["on","sudden",30,1]}"#
+        );
+    }
+
+    #[test]
+    fn command_set_power_with_mode_without_duration_serialization() {
+        let command = command::Command::new(
+            0,
+            Method::BgSetPower(true, Some(Effect::Smooth), None, Some(PowerMode::Rgb)),
+        );
+        let json = serde_json::to_string(&command).unwrap();
+        assert_eq!(
+            json,
+            r#"{"id":0,"method":"bg_set_power","params":["on","smooth",30,2]}"#
+        );
+    }
+
+    #[test]
+    fn command_set_power_without_mode_serialization() {
+        let command = command::Command::new(
+            0,
+            Method::BgSetPower(false, Some(Effect::Sudden), Some(30), None),
+        );
+        let json = serde_json::to_string(&command).unwrap();
+        assert_eq!(
+            json,
+            r#"{"id":0,"method":"bg_set_power","params":["off","sudden",30]}"#
+        );
+    }
+
+    #[test]
+    fn macro_serialization_round_trip() {
+        let r#macro = Macro::new()
+            .then(Method::Toggle)
+            .then_wait(
+                Method::SetRgb(16711680, Some(Effect::Smooth), Some(500)),
+                std::time::Duration::from_millis(500),
+            )
+            .then(Method::SetPower(false, None, None, None));
+        let json = serde_json::to_string(&r#macro).unwrap();
+        assert_eq!(serde_json::from_str::<Macro>(&json).unwrap(), r#macro);
+    }
+
+    #[test]
+    fn macro_with_read_step_round_trip() {
+        let r#macro = Macro::new()
+            .then_wait(
+                Method::GetProps(vec![Property::Power, Property::Bright]),
+                std::time::Duration::from_millis(250),
+            )
+            .then(Method::SetBright(50, None, None));
+        let json = serde_json::to_string(&r#macro).unwrap();
+        assert_eq!(
+            json,
+            concat!(
+                r#"{"steps":[{"method":"get_prop","params":["power","bright"],"delay":250},"#,
+                r#"{"method":"set_bright","params":[50],"delay":null}]}"#
+            )
+        );
+        assert_eq!(serde_json::from_str::<Macro>(&json).unwrap(), r#macro);
+
+        // the delay may be left out
+        let step: crate::method::MacroStep =
+            serde_json::from_str(r#"{"method":"toggle","params":[]}"#).unwrap();
+        assert_eq!(step.method, Method::Toggle);
+        assert_eq!(step.delay, None);
+    }
+
+    #[test]
+    fn command_transition() {
+        let command: command::Command =
+            serde_json::from_str(r#"{"id":0,"method":"set_rgb","params":[16711680,"smooth",500]}"#)
+                .unwrap();
+        assert_eq!(
+            command.method,
+            Method::SetRgb(16711680, Some(Effect::Smooth), Some(500))
+        );
+        assert_eq!(
+            command.transition(),
+            Some(Transition {
+                effect: Effect::Smooth,
+                duration: Some(500)
+            })
+        );
+
+        let command = command::Command::new(0, Method::SetBright(50, None, None));
+        assert_eq!(command.transition(), None);
+
+        let command = command::Command::new(0, Method::Toggle);
+        assert_eq!(command.transition(), None);
+    }
+
+    #[test]
+    fn push_transition_params() {
+        for (effect, duration, expected) in [
+            (None, None, serde_json::json!([])),
+            (None, Some(500), serde_json::json!([])),
+            (Some(Effect::Sudden), None, serde_json::json!(["sudden"])),
+            (
+                Some(Effect::Smooth),
+                Some(500),
+                serde_json::json!(["smooth", 500]),
+            ),
+        ] {
+            let mut params = Vec::new();
+            crate::method::push_transition(&mut params, effect, duration, false);
+            assert_eq!(serde_json::Value::from(params), expected);
+        }
+
+        for (effect, duration, expected) in [
+            (None, None, serde_json::json!(["sudden", 30])),
+            (
+                Some(Effect::Smooth),
+                None,
+                serde_json::json!(["smooth", 30]),
+            ),
+            (None, Some(500), serde_json::json!(["sudden", 500])),
+        ] {
+            let mut params = Vec::new();
+            crate::method::push_transition(&mut params, effect, duration, true);
+            assert_eq!(serde_json::Value::from(params), expected);
+        }
+
+        let command = command::Command::new(0, Method::SetRgb(255, None, Some(500)));
+        assert_eq!(
+            serde_json::to_string(&command).unwrap(),
+            r#"{"id":0,"method":"set_rgb","params":[255]}"#
+        );
+    }
+
+    #[test]
+    fn named_colors_lookup() {
+        assert_eq!(colors::lookup("red"), Some((255, 0, 0)));
+        assert_eq!(colors::lookup("DodgerBlue"), Some((30, 144, 255)));
+        assert_eq!(colors::lookup("tomato"), Some((255, 99, 71)));
+        assert_eq!(colors::lookup("notacolor"), None);
+        assert!(colors::NAMED_COLORS.windows(2).all(|w| w[0].0 < w[1].0));
+    }
+
+    #[test]
+    fn command_set_ps_serialization() {
+        for (mode, json) in [
+            (
+                PowerOnMode::LastState,
+                r#"{"id":0,"method":"set_ps","params":["cfg_init_power","0"]}"#,
+            ),
+            (
+                PowerOnMode::SavedState,
+                r#"{"id":0,"method":"set_ps","params":["cfg_init_power","1"]}"#,
+            ),
+        ] {
+            let command = command::Command::new(
+                0,
+                Method::SetPs(PowerOnMode::KEY.to_string(), mode.value().to_string()),
+            );
+            assert_eq!(serde_json::to_string(&command).unwrap(), json);
+            assert_eq!(
+                serde_json::from_str::<command::Command>(json).unwrap(),
+                command
+            );
+        }
+    }
+
+    #[test]
+    fn command_bg_toggle_serialization() {
+        let command = command::Command::new(0, Method::BgToggle);
+        let json = serde_json::to_string(&command).unwrap();
+        assert_eq!(json, r#"{"id":0,"method":"bg_toggle","params":[]}"#);
+        assert_eq!(
+            serde_json::from_str::<command::Command>(&json).unwrap(),
+            command
+        );
+    }
+
+    #[test]
+    fn command_dev_toggle_serialization() {
+        let command = command::Command::new(0, Method::DevToggle);
+        let json = serde_json::to_string(&command).unwrap();
+        assert_eq!(json, r#"{"id":0,"method":"dev_toggle","params":[]}"#);
+        assert_eq!(
+            serde_json::from_str::<command::Command>(&json).unwrap(),
+            command
+        );
+    }
+
+    #[test]
+    fn command_set_name_serialization() {
+        let command = command::Command::new(0, Method::SetName(String::from("my light")));
+        let json = serde_json::to_string(&command).unwrap();
+        assert_eq!(
+            json,
+            r#"{"id":0,"method":"set_name","params":["my light"]}"#
+        );
+        assert_eq!(
+            serde_json::from_str::<command::Command>(&json).unwrap(),
+            command
+        );
+    }
+
+    #[test]
+    fn command_adjust_serialization() {
+        for (method, json) in [
+            (
+                Method::AdjustBright(-20, 500),
+                r#"{"id":0,"method":"adjust_bright","params":[-20,500]}"#,
+            ),
+            (
+                Method::AdjustCt(20, 500),
+                r#"{"id":0,"method":"adjust_ct","params":[20,500]}"#,
+            ),
+            (
+                Method::AdjustColor(100, 500),
+                r#"{"id":0,"method":"adjust_color","params":[100,500]}"#,
+            ),
+            (
+                Method::BgAdjustBright(-100, 0),
+                r#"{"id":0,"method":"bg_adjust_bright","params":[-100,0]}"#,
+            ),
+            (
+                Method::BgAdjustCt(50, 1000),
+                r#"{"id":0,"method":"bg_adjust_ct","params":[50,1000]}"#,
+            ),
+            (
+                Method::BgAdjustColor(-50, 1000),
+                r#"{"id":0,"method":"bg_adjust_color","params":[-50,1000]}"#,
+            ),
+        ] {
+            let command = command::Command::new(0, method);
+            assert_eq!(serde_json::to_string(&command).unwrap(), json);
+            assert_eq!(
+                serde_json::from_str::<command::Command>(json).unwrap(),
+                command
+            );
+        }
+    }
+
+    #[test]
+    fn command_set_adjust_serialization() {
+        let command = command::Command::new(
+            0,
+            Method::SetAdjust(AdjustAction::Increase, AdjustProp::Bright),
+        );
+        let json = serde_json::to_string(&command).unwrap();
+        assert_eq!(
+            json,
+            r#"{"id":0,"method":"set_adjust","params":["increase","bright"]}"#
+        );
+        assert_eq!(
+            serde_json::from_str::<command::Command>(&json).unwrap(),
+            command
+        );
+    }
+
+    #[test]
+    fn command_cron_serialization() {
+        for (method, json) in [
+            (
+                Method::CronAdd(0, 15),
+                r#"{"id":0,"method":"cron_add","params":[0,15]}"#,
+            ),
+            (
+                Method::CronGet(0),
+                r#"{"id":0,"method":"cron_get","params":[0]}"#,
+            ),
+            (
+                Method::CronDel(0),
+                r#"{"id":0,"method":"cron_del","params":[0]}"#,
+            ),
+        ] {
+            let command = command::Command::new(0, method);
+            assert_eq!(serde_json::to_string(&command).unwrap(), json);
+            assert_eq!(
+                serde_json::from_str::<command::Command>(json).unwrap(),
+                command
+            );
+        }
+    }
+
+    #[test]
+    fn test_cron_response_parsing() {
+        let data = r#"{"id":1, "result":[{"type":0,"delay":15,"mix":0}]}"#;
+        let response: CommandResponse = serde_json::from_str(data).unwrap();
+        assert_eq!(
+            response.result,
+            [CommandResult::Json(
+                serde_json::json!({"type": 0, "delay": 15, "mix": 0})
+            )]
+        );
+        // structured results are serialized as they were received
+        assert_eq!(
+            serde_json::to_string(&response.result).unwrap(),
+            r#"[{"delay":15,"mix":0,"type":0}]"#
+        );
+        assert_eq!(
+            command::CronEntry::from_response(&response).unwrap(),
+            [command::CronEntry {
+                type_: 0,
+                delay: 15,
+                mix: 0,
+            }]
+        );
+
+        let response: CommandResponse =
+            serde_json::from_str(r#"{"id":1, "result":["ok"]}"#).unwrap();
+        assert!(command::CronEntry::from_response(&response).is_err());
+    }
+
+    #[test]
+    fn command_set_scene_serialization() {
+        for (method, json) in [
+            (
+                Method::SetScene(Scene::Color(65280, 70)),
+                r#"{"id":0,"method":"set_scene","params":["color",65280,70]}"#,
+            ),
+            (
+                Method::SetScene(Scene::Hsv(300, 70, 100)),
+                r#"{"id":0,"method":"set_scene","params":["hsv",300,70,100]}"#,
+            ),
+            (
+                Method::SetScene(Scene::Ct(5400, 100)),
+                r#"{"id":0,"method":"set_scene","params":["ct",5400,100]}"#,
+            ),
+            (
+                Method::SetScene(Scene::ColorFlow(0, 0, String::from("500,1,255,100"))),
+                r#"{"id":0,"method":"set_scene","params":["cf",0,0,"500,1,255,100"]}"#,
+            ),
+            (
+                Method::SetScene(Scene::AutoDelayOff(50, 5)),
+                r#"{"id":0,"method":"set_scene","params":["auto_delay_off",50,5]}"#,
+            ),
+            (
+                Method::BgSetScene(Scene::Color(255, 10)),
+                r#"{"id":0,"method":"bg_set_scene","params":["color",255,10]}"#,
+            ),
+        ] {
+            let command = command::Command::new(0, method);
+            assert_eq!(serde_json::to_string(&command).unwrap(), json);
+            assert_eq!(
+                serde_json::from_str::<command::Command>(json).unwrap(),
+                command
+            );
+        }
+    }
+
+    #[test]
+    fn command_round_trip_all_methods() {
+        let methods = [
+            Method::GetProp(Property::Bright),
+            Method::GetProps(vec![Property::Power, Property::Rgb]),
+            Method::Toggle,
+            Method::BgToggle,
+            Method::DevToggle,
+            Method::SetPower(true, Some(Effect::Smooth), Some(500), Some(PowerMode::Ct)),
+            Method::SetPower(false, None, None, None),
+            Method::SetRgb(16711680, Some(Effect::Smooth), Some(500)),
+            Method::SetHsv(255, 45, Some(Effect::Sudden), None),
+            Method::SetBright(50, None, None),
+            Method::SetDefault,
+            Method::BgSetRgb(255, Some(Effect::Smooth), Some(300)),
+            Method::BgSetHsv(120, 100, None, None),
+            Method::BgSetDefault,
+            Method::BgSetPower(true, Some(Effect::Sudden), Some(30), None),
+            Method::BgSetBright(10, Some(Effect::Smooth), Some(1000)),
+            Method::SetCtAbx(3500, Some(Effect::Smooth), Some(500)),
+            Method::BgSetCtAbx(6500, None, None),
+            Method::AdjustBright(-20, 500),
+            Method::AdjustCt(20, 500),
+            Method::AdjustColor(10, 500),
+            Method::SetAdjust(AdjustAction::Circle, AdjustProp::Color),
+            Method::BgSetAdjust(AdjustAction::Decrease, AdjustProp::Ct),
+            Method::BgAdjustBright(30, 100),
+            Method::BgAdjustCt(-30, 100),
+            Method::BgAdjustColor(50, 100),
+            Method::StartCf(4, 2, String::from("1000,2,2700,100,500,1,255,10")),
+            Method::StopCf,
+            Method::SetName(String::from("my light")),
+            Method::SetScene(Scene::Hsv(300, 70, 100)),
+            Method::BgSetScene(Scene::ColorFlow(0, 1, String::from("500,1,255,100"))),
+            Method::CronAdd(0, 15),
+            Method::CronGet(0),
+            Method::CronDel(0),
+            Method::SetMusic(1, Some(String::from("192.168.0.2")), Some(54321)),
+            Method::SetMusic(0, None, None),
+        ];
+
+        for method in methods {
+            let command = command::Command::new(7, method);
+            let json = serde_json::to_string(&command).unwrap();
+            assert_eq!(
+                serde_json::from_str::<command::Command>(&json).unwrap(),
+                command,
+                "{}",
+                json
+            );
+        }
+
+        // a single property is read with the same command as GetProp, so it can only be parsed back as GetProp
+        let command = command::Command::new(7, Method::GetProps(vec![Property::Power]));
+        let json = serde_json::to_string(&command).unwrap();
+        let parsed = serde_json::from_str::<command::Command>(&json).unwrap();
+        assert_eq!(parsed.method, Method::GetProp(Property::Power));
+        assert_eq!(serde_json::to_string(&parsed).unwrap(), json);
+    }
+
+    #[test]
+    fn raw_command_conversion_tells_get_prop_and_get_props_apart() {
+        let raw = command::RawCommand {
+            id: 1,
+            method: String::from("get_prop"),
+            params: vec!["power".into(), "bright".into()],
+        };
+        assert_eq!(
+            Method::try_from(&raw).unwrap(),
+            Method::GetProps(vec![Property::Power, Property::Bright])
+        );
+
+        let raw = command::RawCommand {
+            id: 1,
+            method: String::from("get_prop"),
+            params: vec!["power".into()],
+        };
+        assert_eq!(
+            Method::try_from(&raw).unwrap(),
+            Method::GetProp(Property::Power)
+        );
+    }
+
+    #[test]
+    fn command_deserialization_restores_params() {
+        let command: command::Command =
+            serde_json::from_str(r#"{"id":1,"method":"set_rgb","params":[16711680,"smooth",500]}"#)
+                .unwrap();
+        assert_eq!(
+            command.method,
+            Method::SetRgb(16711680, Some(Effect::Smooth), Some(500))
+        );
+    }
+
+    #[test]
+    fn malformed_command_deserialization_fails() {
+        for json in [
+            r#"{"id":1,"method":"not_a_method","params":[]}"#,
+            r#"{"id":1,"method":"set_rgb","params":["red"]}"#,
+            r#"{"id":1,"method":"set_rgb","params":[]}"#,
+            r#"{"id":1,"method":"set_rgb","params":[255,"fast"]}"#,
+            r#"{"id":1,"method":"set_power","params":[true]}"#,
+            r#"{"id":1,"method":"set_power","params":["maybe"]}"#,
+            r#"{"id":1,"method":"set_power","params":[]}"#,
+            r#"{"id":1,"method":"set_scene","params":["bogus"]}"#,
+            r#"{"id":1,"method":"set_scene","params":["color",255]}"#,
+            r#"{"id":1,"method":"set_scene","params":[]}"#,
+            r#"{"id":1,"method":"get_prop","params":["power",5]}"#,
+            r#"{"id":1,"method":"start_cf","params":[4,2]}"#,
+        ] {
+            assert!(
+                serde_json::from_str::<command::Command>(json).is_err(),
+                "{}",
+                json
+            );
+        }
+    }
+
+    #[test]
+    fn flow_expression_serialization() {
+        let expression = FlowExpression::new()
+            .then(FlowTuple::color_temperature(1000, 2700, 100))
+            .then(FlowTuple::sleep(500))
+            .then(FlowTuple::color(500, Device::get_rgb_color(0, 0, 255), -1));
+        assert_eq!(
+            expression.to_string(),
+            "1000,2,2700,100,500,7,0,0,500,1,255,-1"
+        );
+        assert_eq!(FlowExpression::new().to_string(), "");
+    }
+
+    #[test]
+    fn command_start_cf_serialization() {
+        let expression = FlowExpression::new()
+            .then(FlowTuple::color_temperature(1000, 2700, 100))
+            .then(FlowTuple::color(500, 255, 10));
+        let command = command::Command::new(0, Method::StartCf(4, 2, expression.to_string()));
+        let json = serde_json::to_string(&command).unwrap();
+        assert_eq!(
+            json,
+            r#"{"id":0,"method":"start_cf","params":[4,2,"1000,2,2700,100,500,1,255,10"]}"#
+        );
+
+        let command = command::Command::new(0, Method::StopCf);
+        let json = serde_json::to_string(&command).unwrap();
+        assert_eq!(json, r#"{"id":0,"method":"stop_cf","params":[]}"#);
+    }
+
     #[test]
     fn test_response_parsing() {
         let data = "{\"id\":1, \"result\":[\"ok\"]}";
@@ -93,4 +623,53 @@ mod tests {
         assert_eq!(response.result.len(), 1);
         assert_eq!(response.result[0], CommandResult::Ok);
     }
+
+    #[test]
+    fn test_value_response_parsing() {
+        let data = r#"{"id":1, "result":["50", "16711680", "", 2]}"#;
+        let response: CommandResponse = serde_json::from_str(data).unwrap();
+        assert_eq!(
+            response.result,
+            [
+                CommandResult::Value(String::from("50")),
+                CommandResult::Value(String::from("16711680")),
+                CommandResult::Value(String::new()),
+                CommandResult::Value(String::from("2")),
+            ]
+        );
+        assert_eq!(
+            serde_json::to_string(&response.result).unwrap(),
+            r#"["50","16711680","","2"]"#
+        );
+    }
+
+    #[test]
+    fn test_state_response_parsing() {
+        for (data, expected) in [
+            (r#"{"id":1, "result":["ok"]}"#, CommandResult::Ok),
+            (r#"{"id":1, "result":["on"]}"#, CommandResult::On),
+            (r#"{"id":1, "result":["off"]}"#, CommandResult::Off),
+        ] {
+            let response: CommandResponse = serde_json::from_str(data).unwrap();
+            assert_eq!(response.result, [expected]);
+            assert_eq!(response.error, None);
+        }
+    }
+
+    #[test]
+    fn test_notification_method_parsing() {
+        for (data, expected) in [
+            (
+                r#"{"method":"props","params":{"power":"on"}}"#,
+                NotificationMethod::Props,
+            ),
+            (
+                r#"{"method":"bg_event","params":{}}"#,
+                NotificationMethod::Other(String::from("bg_event")),
+            ),
+        ] {
+            let notification: NotificationResult = serde_json::from_str(data).unwrap();
+            assert_eq!(notification.method, expected);
+        }
+    }
 }
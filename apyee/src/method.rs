@@ -1,66 +1,437 @@
 use crate::command::RawCommand;
 use crate::property::Property;
 use apyee_macros::{FromRawCommand, GetParams, IntoJsonValue};
-use serde::{Deserialize, Serialize};
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use std::time::Duration;
 
 /// Methods to be called on a device.
+///
+/// The effect and duration of a transition, marked with `#[transition]`, are positional params,
+/// so a duration is only sent together with an effect: e.g. `Method::SetRgb(255, None, Some(500))`
+/// is sent as `[255]`, without the duration.
 #[derive(Serialize, Deserialize, Clone, GetParams, PartialEq, Eq, Debug, FromRawCommand)]
 #[serde(rename_all = "snake_case")]
 pub enum Method {
     /// Get the specified property value.
     GetProp(Property),
     /// Gets multiple property values.
-    #[serde(rename = "get_prop")]
+    ///
+    /// Sent as `get_prop` like [`Method::GetProp`], so a command reading a single property is indistinguishable from it
+    /// and is parsed back as [`Method::GetProp`].
+    #[serde(rename = "get_prop", skip_deserializing)]
+    #[from_raw_guard(raw.params.len() != 1)]
     GetProps(Vec<Property>),
 
     /// Toggle the power state of the device.
     Toggle,
 
+    /// [`Method::Toggle`]
+    BgToggle,
+
+    /// Toggle the power state of the main and background light at once.
+    /// Only supported by devices with both lights, e.g. some ceiling lights.
+    DevToggle,
+
     /// Set the power state of the device.
-    SetPower(bool, Option<Effect>, Option<i32>),
+    ///
+    /// # Arguments
+    /// * `mode` - The [`PowerMode`] to switch into when turning on. Not every device accepts it, so it is only sent when given. Since the parameters are positional, the effect and duration are always sent before it, defaulting to [`Effect::Sudden`] and 30 milliseconds.
+    SetPower(
+        bool,
+        #[transition] Option<Effect>,
+        Option<i32>,
+        Option<PowerMode>,
+    ),
 
     /// Set the RGB Color of the light.
-    SetRgb(i32, Option<Effect>, Option<i32>),
+    SetRgb(i32, #[transition] Option<Effect>, Option<i32>),
 
     /// Set the HSV Color of the light.
     ///
     /// # Arguments
     /// * `hue` - The hue of the color. It should be expressed in decimal integer ranges from 0 to 359.
     /// * `sat` - The saturation of the color. It's range is 0 to 100.
-    SetHsv(i32, i32, Option<Effect>, Option<i32>),
+    SetHsv(i32, i32, #[transition] Option<Effect>, Option<i32>),
 
     /// Set the brightness of the light.
     ///
     /// # Arguments
     /// * `bright` - The brightness of the light. It's range is 1 to 100. The brightness is a percentage instead of a absolute value. 100 means maximum brightness while 1 means the minimum brightness.
-    SetBright(i32, Option<Effect>, Option<i32>),
+    SetBright(i32, #[transition] Option<Effect>, Option<i32>),
 
     /// This method is used to save current state of smart LED in persistent memory. So if user powers off and then powers on the smart LED again (hard power reset), the smart LED will show last saved state.
     SetDefault,
 
     /// [`Method::SetRgb`]
-    BgSetRgb(i32, Option<Effect>, Option<i32>),
+    BgSetRgb(i32, #[transition] Option<Effect>, Option<i32>),
 
     /// [`Method::SetHsv`]
-    BgSetHsv(i32, i32, Option<Effect>, Option<i32>),
+    BgSetHsv(i32, i32, #[transition] Option<Effect>, Option<i32>),
 
     /// Saves current Background state; see [`Method::SetDefault`] for more info.
     BgSetDefault,
 
     /// [`Method::SetPower`]
-    BgSetPower(bool, Option<Effect>, Option<i32>),
+    BgSetPower(
+        bool,
+        #[transition] Option<Effect>,
+        Option<i32>,
+        Option<PowerMode>,
+    ),
 
     /// [`Method::SetRgb`]
-    BgSetBright(i32, Option<Effect>, Option<i32>),
+    BgSetBright(i32, #[transition] Option<Effect>, Option<i32>),
 
     /// Set color temperature of the light.
     ///
     /// # Arguments
     /// * `ct_value` is the target color temperature. The type is integer and range is 1700 ~ 6500 (k).
-    SetCtAbx(i32, Option<Effect>, Option<i32>),
+    SetCtAbx(i32, #[transition] Option<Effect>, Option<i32>),
 
     /// [`Method::SetCtAbx`]
-    BgSetCtAbx(i32, Option<Effect>, Option<i32>),
+    BgSetCtAbx(i32, #[transition] Option<Effect>, Option<i32>),
+
+    /// Adjust the brightness relative to its current value.
+    ///
+    /// # Arguments
+    /// * `percentage` - The percentage to adjust by, -100 ~ 100.
+    /// * `duration` - The duration of the adjustment in milliseconds.
+    AdjustBright(i32, i32),
+
+    /// Adjust the color temperature relative to its current value.
+    ///
+    /// # Arguments
+    /// * `percentage` - The percentage to adjust by, -100 ~ 100.
+    /// * `duration` - The duration of the adjustment in milliseconds.
+    AdjustCt(i32, i32),
+
+    /// Adjust the color relative to its current value.
+    ///
+    /// # Arguments
+    /// * `percentage` - The percentage to adjust by, -100 ~ 100.
+    /// * `duration` - The duration of the adjustment in milliseconds.
+    AdjustColor(i32, i32),
+
+    /// Adjust a property without knowing its current value.
+    ///
+    /// # Arguments
+    /// * `action` - The [`AdjustAction`] to take. [`AdjustProp::Color`] only supports [`AdjustAction::Circle`].
+    /// * `prop` - The [`AdjustProp`] to adjust.
+    SetAdjust(AdjustAction, AdjustProp),
+
+    /// [`Method::SetAdjust`]
+    BgSetAdjust(AdjustAction, AdjustProp),
+
+    /// [`Method::AdjustBright`]
+    BgAdjustBright(i32, i32),
+
+    /// [`Method::AdjustCt`]
+    BgAdjustCt(i32, i32),
+
+    /// [`Method::AdjustColor`]
+    BgAdjustColor(i32, i32),
+
+    /// Start a color flow.
+    ///
+    /// # Arguments
+    /// * `count` - The number of state changes before the flow stops, 0 for an infinite loop.
+    /// * `action` - The [`crate::flow::FlowAction`] taken after the flow stops.
+    /// * `flow_expression` - The [`crate::flow::FlowExpression`] describing the flow.
+    StartCf(i32, i32, String),
+
+    /// Stop a running color flow.
+    StopCf,
+
+    /// Set the name of the device, which is stored on the device and reported by [`Property::Name`].
+    SetName(String),
+
+    /// Set the device directly into a [`Scene`], no matter if it is on or off.
+    /// Unlike sending multiple commands, this changes all values of the scene at once without flickering.
+    SetScene(#[flatten_params] Scene),
+
+    /// [`Method::SetScene`]
+    BgSetScene(#[flatten_params] Scene),
+
+    /// Add a timer to the device.
+    ///
+    /// # Arguments
+    /// * `type` - The type of the timer, only 0 (power off) is supported.
+    /// * `value` - The delay of the timer in minutes.
+    CronAdd(i32, i32),
+
+    /// Get the timer of the given type, see [`Method::CronAdd`].
+    /// The result is parsed with [`crate::command::CronEntry::from_response`].
+    CronGet(i32),
+
+    /// Delete the timer of the given type, see [`Method::CronAdd`].
+    CronDel(i32),
+
+    /// Set a power setting of the device, e.g. its [`PowerOnMode`].
+    ///
+    /// `set_ps` isn't part of the [Yeelight Inter-Operation Specification](https://home.yeelight.de/site/templates/downloads/yeelight_inter-operation-spec.pdf),
+    /// whose model table lists the methods each model supports, so no model or firmware version is documented to support it.
+    /// Devices without support respond with an error.
+    ///
+    /// # Arguments
+    /// * `key` - The name of the setting, e.g. `cfg_init_power`.
+    /// * `value` - The value of the setting.
+    SetPs(String, String),
+
+    /// Start or stop music mode, in which the device connects to a server and accepts commands
+    /// over that connection without rate limiting.
+    ///
+    /// # Arguments
+    /// * `action` - 1 to start music mode, 0 to stop it.
+    /// * `host` - The IP address of the server the device connects to, only used when starting.
+    /// * `port` - The port of the server the device connects to, only used when starting.
+    SetMusic(i32, Option<String>, Option<i32>),
+}
+
+impl Method {
+    /// Returns the [`Transition`] of the method, if it has one and its effect is set.
+    pub fn transition(&self) -> Option<Transition> {
+        let (effect, duration) = match self {
+            Method::SetPower(_, effect, duration, _)
+            | Method::BgSetPower(_, effect, duration, _)
+            | Method::SetRgb(_, effect, duration)
+            | Method::BgSetRgb(_, effect, duration)
+            | Method::SetHsv(_, _, effect, duration)
+            | Method::BgSetHsv(_, _, effect, duration)
+            | Method::SetBright(_, effect, duration)
+            | Method::BgSetBright(_, effect, duration)
+            | Method::SetCtAbx(_, effect, duration)
+            | Method::BgSetCtAbx(_, effect, duration) => (effect, duration),
+            _ => return None,
+        };
+
+        effect.clone().map(|effect| Transition {
+            effect,
+            duration: *duration,
+        })
+    }
+
+    /// Returns whether the method reads values from the device instead of changing its state.
+    pub(crate) fn is_read(&self) -> bool {
+        matches!(
+            self,
+            Method::GetProp(_) | Method::GetProps(_) | Method::CronGet(_)
+        )
+    }
+
+    /// Replaces a [`Effect::Smooth`] transition with [`Effect::Sudden`], for devices that don't support smooth transitions.
+    /// Returns whether the method was changed.
+    pub(crate) fn make_sudden(&mut self) -> bool {
+        if let Method::SetPower(_, effect, _, _)
+        | Method::BgSetPower(_, effect, _, _)
+        | Method::SetRgb(_, effect, _)
+        | Method::BgSetRgb(_, effect, _)
+        | Method::SetHsv(_, _, effect, _)
+        | Method::BgSetHsv(_, _, effect, _)
+        | Method::SetBright(_, effect, _)
+        | Method::BgSetBright(_, effect, _)
+        | Method::SetCtAbx(_, effect, _)
+        | Method::BgSetCtAbx(_, effect, _) = self
+        {
+            if *effect == Some(Effect::Smooth) {
+                *effect = Some(Effect::Sudden);
+                return true;
+            }
+        }
+
+        false
+    }
+}
+
+/// A state set at once through [`Method::SetScene`].
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum Scene {
+    /// An RGB color and a brightness, 1 ~ 100.
+    Color(i32, i32),
+    /// A hue, 0 ~ 359, a saturation, 0 ~ 100, and a brightness, 1 ~ 100.
+    Hsv(i32, i32, i32),
+    /// A color temperature, 1700 ~ 6500 (k), and a brightness, 1 ~ 100.
+    Ct(i32, i32),
+    /// A color flow, see [`Method::StartCf`] for its arguments.
+    ColorFlow(i32, i32, String),
+    /// A brightness, 1 ~ 100, with a timer turning the device off after the given number of minutes.
+    AutoDelayOff(i32, i32),
+}
+
+impl Scene {
+    /// Returns the positional params of the scene, starting with its class, e.g. `["color", 65280, 70]`.
+    pub(crate) fn get_params(&self) -> Vec<serde_json::Value> {
+        match self {
+            Scene::Color(rgb, bright) => vec!["color".into(), (*rgb).into(), (*bright).into()],
+            Scene::Hsv(hue, sat, bright) => {
+                vec!["hsv".into(), (*hue).into(), (*sat).into(), (*bright).into()]
+            }
+            Scene::Ct(ct, bright) => vec!["ct".into(), (*ct).into(), (*bright).into()],
+            Scene::ColorFlow(count, action, flow_expression) => vec![
+                "cf".into(),
+                (*count).into(),
+                (*action).into(),
+                flow_expression.as_str().into(),
+            ],
+            Scene::AutoDelayOff(bright, minutes) => {
+                vec!["auto_delay_off".into(), (*bright).into(), (*minutes).into()]
+            }
+        }
+    }
+
+    /// Parses a scene from its positional params, see [`Scene::get_params`].
+    ///
+    /// # Errors
+    /// * `serde_json::Error` - If the class is unknown, or a value is missing or has the wrong type.
+    pub(crate) fn from_params(params: &[serde_json::Value]) -> Result<Self, serde_json::Error> {
+        let param = |i: usize| match params.get(i) {
+            Some(value) => Ok(value),
+            None => Err(de::Error::custom(format!(
+                "value {} of the scene is missing",
+                i + 1
+            ))),
+        };
+        let int = |i: usize| param(i).and_then(i32::deserialize);
+
+        Ok(match param(0)?.as_str() {
+            Some("color") => Scene::Color(int(1)?, int(2)?),
+            Some("hsv") => Scene::Hsv(int(1)?, int(2)?, int(3)?),
+            Some("ct") => Scene::Ct(int(1)?, int(2)?),
+            Some("cf") => Scene::ColorFlow(int(1)?, int(2)?, String::deserialize(param(3)?)?),
+            Some("auto_delay_off") => Scene::AutoDelayOff(int(1)?, int(2)?),
+            _ => {
+                return Err(de::Error::custom(format!(
+                    "unknown scene class {}",
+                    param(0)?
+                )))
+            }
+        })
+    }
+}
+
+/// The duration sent for a transition which has to be sent without a given duration, the minimum the device accepts.
+pub(crate) const DEFAULT_TRANSITION_DURATION: i32 = 30;
+
+/// Appends the effect and duration of a transition to the params of a method.
+/// Trailing absent values are omitted, and a duration without an effect is omitted as well,
+/// since the params are positional and a duration only applies to an effect.
+///
+/// If the transition is `followed` by another param, both values are sent so that param keeps its position,
+/// defaulting to [`Effect::Sudden`] and [`DEFAULT_TRANSITION_DURATION`].
+pub(crate) fn push_transition(
+    params: &mut Vec<serde_json::Value>,
+    effect: Option<Effect>,
+    duration: Option<i32>,
+    followed: bool,
+) {
+    if followed {
+        params.push(serde_json::Value::from(effect.unwrap_or(Effect::Sudden)));
+        params.push(serde_json::Value::from(
+            duration.unwrap_or(DEFAULT_TRANSITION_DURATION),
+        ));
+        return;
+    }
+
+    let Some(effect) = effect else {
+        return;
+    };
+
+    params.push(serde_json::Value::from(effect));
+    if let Some(duration) = duration {
+        params.push(serde_json::Value::from(duration));
+    }
+}
+
+/// The transition of a method changing a property, consisting of its effect and duration.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
+pub struct Transition {
+    /// The effect of the transition.
+    pub effect: Effect,
+    /// The duration of the transition in milliseconds, if given.
+    pub duration: Option<i32>,
+}
+
+/// A single step of a [`Macro`].
+/// It is serialized with the method in the form it is sent to the device and the delay in milliseconds,
+/// e.g. `{"method":"set_bright","params":[50],"delay":500}`.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
+#[serde(try_from = "RawMacroStep", into = "RawMacroStep")]
+pub struct MacroStep {
+    /// The method to execute.
+    pub method: Method,
+    /// The delay to wait for after executing the method, if any.
+    pub delay: Option<Duration>,
+}
+
+/// The serialized form of a [`MacroStep`].
+#[derive(Serialize, Deserialize)]
+struct RawMacroStep {
+    method: String,
+    params: Vec<serde_json::Value>,
+    #[serde(default, with = "crate::device::option_duration_millis")]
+    delay: Option<Duration>,
+}
+
+impl From<MacroStep> for RawMacroStep {
+    fn from(step: MacroStep) -> Self {
+        Self {
+            method: serde_variant::to_variant_name(&step.method)
+                .unwrap()
+                .to_string(),
+            params: step.method.get_params(),
+            delay: step.delay,
+        }
+    }
+}
+
+impl TryFrom<RawMacroStep> for MacroStep {
+    type Error = serde_json::Error;
+
+    fn try_from(raw: RawMacroStep) -> Result<Self, Self::Error> {
+        let command = RawCommand {
+            id: 0,
+            method: raw.method,
+            params: raw.params,
+        };
+
+        Ok(Self {
+            method: Method::try_from(&command)?,
+            delay: raw.delay,
+        })
+    }
+}
+
+/// An ordered sequence of [`Method`]s with optional delays in between, executed by [`crate::device::Device::run_macro`].
+/// Macros can be serialized, e.g. to store user-defined button actions.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug, Default)]
+pub struct Macro {
+    /// The steps of the macro, in order of execution.
+    pub steps: Vec<MacroStep>,
+}
+
+impl Macro {
+    /// Creates a new, empty macro.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Appends a [`Method`] to the macro.
+    pub fn then(mut self, method: Method) -> Self {
+        self.steps.push(MacroStep {
+            method,
+            delay: None,
+        });
+        self
+    }
+
+    /// Appends a [`Method`] to the macro, followed by a delay before the next step.
+    pub fn then_wait(mut self, method: Method, delay: Duration) -> Self {
+        self.steps.push(MacroStep {
+            method,
+            delay: Some(delay),
+        });
+        self
+    }
 }
 
 /// The effect to use when setting a certain property.
@@ -72,3 +443,92 @@ pub enum Effect {
     /// Values will be changed gradually, the total time of gradual change is specified by the duration.
     Smooth,
 }
+
+/// The state a device restores when it is powered on through its power supply, set with [`Method::SetPs`].
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum PowerOnMode {
+    /// Restore the state the device was in before it lost power.
+    LastState,
+    /// Restore the state saved with [`Method::SetDefault`].
+    SavedState,
+}
+
+impl PowerOnMode {
+    /// The key of the power setting configuring the power-on mode.
+    pub const KEY: &'static str = "cfg_init_power";
+
+    /// Returns the value of the power setting for this mode.
+    pub fn value(self) -> &'static str {
+        match self {
+            PowerOnMode::LastState => "0",
+            PowerOnMode::SavedState => "1",
+        }
+    }
+}
+
+/// The direction of an adjustment through [`Method::SetAdjust`].
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum AdjustAction {
+    /// Increase the property.
+    Increase,
+    /// Decrease the property.
+    Decrease,
+    /// Increase the property, wrapping around to the minimum after reaching the maximum.
+    Circle,
+}
+
+/// The property adjusted through [`Method::SetAdjust`].
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum AdjustProp {
+    /// The brightness.
+    Bright,
+    /// The color temperature.
+    Ct,
+    /// The color, only supports [`AdjustAction::Circle`].
+    Color,
+}
+
+/// The mode a device switches into when it is turned on through [`Method::SetPower`].
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum PowerMode {
+    /// Turn on into the last state.
+    Normal = 0,
+    /// Turn on into color temperature mode.
+    Ct = 1,
+    /// Turn on into RGB mode.
+    Rgb = 2,
+    /// Turn on into HSV mode.
+    Hsv = 3,
+    /// Turn on into color flow mode.
+    ColorFlow = 4,
+    /// Turn on into night light (moonlight) mode, only supported by ceiling lights.
+    NightLight = 5,
+}
+
+impl Serialize for PowerMode {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.serialize_u8(*self as u8)
+    }
+}
+
+impl<'de> Deserialize<'de> for PowerMode {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        match u8::deserialize(deserializer)? {
+            0 => Ok(PowerMode::Normal),
+            1 => Ok(PowerMode::Ct),
+            2 => Ok(PowerMode::Rgb),
+            3 => Ok(PowerMode::Hsv),
+            4 => Ok(PowerMode::ColorFlow),
+            5 => Ok(PowerMode::NightLight),
+            mode => Err(de::Error::custom(format!("unknown power mode {}", mode))),
+        }
+    }
+}
@@ -0,0 +1,57 @@
+use std::{
+    net::SocketAddr,
+    sync::{Mutex, PoisonError},
+};
+
+static CONNECTED: Mutex<Vec<SocketAddr>> = Mutex::new(Vec::new());
+
+/// A process-wide registration of a connected device address, removed again when dropped.
+pub(crate) struct Registration {
+    address: SocketAddr,
+    duplicate: bool,
+}
+
+impl Registration {
+    /// Registers the address, noting whether another registration for it already exists.
+    pub(crate) fn register(address: SocketAddr) -> Self {
+        let mut connected = CONNECTED.lock().unwrap_or_else(PoisonError::into_inner);
+        let duplicate = connected.contains(&address);
+        connected.push(address);
+
+        Self { address, duplicate }
+    }
+
+    /// Returns whether another registration for the address existed when this one was registered.
+    pub(crate) fn is_duplicate(&self) -> bool {
+        self.duplicate
+    }
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        let mut connected = CONNECTED.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(index) = connected.iter().position(|addr| *addr == self.address) {
+            connected.swap_remove(index);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn duplicate_registration_is_detected() {
+        let address = "127.0.0.1:1".parse().unwrap();
+
+        let first = Registration::register(address);
+        assert!(!first.is_duplicate());
+        let second = Registration::register(address);
+        assert!(second.is_duplicate());
+
+        drop(first);
+        drop(second);
+        let third = Registration::register(address);
+        assert!(!third.is_duplicate());
+    }
+}